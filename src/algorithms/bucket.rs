@@ -8,15 +8,30 @@
 // at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
 // at https://opensource.org/licenses/MIT.
 
-use crate::{algorithms::{Id, Token}, error::{Error, Result}};
+use crate::{algorithms::{Id, RateAlgorithm, Token, TokenBucket}, clock::Clock, error::{Error, Result, ThrottleReason}};
 use parking_lot::{Mutex, lock_api::MutexGuard};
-use std::{cmp::min, sync::atomic::{AtomicUsize, Ordering}};
+use std::{
+    cmp::{max, min},
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc},
+    time::{Duration, Instant}
+};
 
 /// A bucket has a certain capacity which is made available as `Token`s
 /// containing quantities equal to capacity divided by parts.
 ///
 /// With every part added, future `get` calls will return tokens with a
-/// quantity equal to capacity / (parts + 1).
+/// quantity equal to capacity / (parts + 1). Parts can be given different
+/// weights, in which case a part's quantity is capacity * weight / total
+/// weight of all parts.
+///
+/// A part's first token of a time index is based on the capacity at the
+/// start of the time index, so it does not depend on the order in which
+/// parts call `get`. The remainder of the division is spread by rounding
+/// up the shares of as many parts as necessary, in round-robin order of
+/// IDs, so that every part takes its turn at the remainder and the full
+/// capacity is handed out.
 ///
 /// While the available capacity can not be blocked by inactive parts, i.e.
 /// those which do not call `get`, it requires more `get` calls to retrieve
@@ -24,91 +39,1046 @@ use std::{cmp::min, sync::atomic::{AtomicUsize, Ordering}};
 ///
 // TODO: In order to avoid continuous slowdown in the rate limiter itself,
 // track usage per part and remove stale parts if necessary.
-#[derive(Debug)]
 pub struct Bucket {
-    maximum: usize, // maximum capacity
-    idgen: AtomicUsize, // id generator
+    maximum: AtomicUsize, // maximum capacity
+    max_parts: AtomicUsize, // maximum number of parts
+    ramp: usize, // number of time indices over which new parts reach their full share
+    fair: AtomicBool, // unlock fairly, i.e. hand the lock over to waiting threads
+    paused: AtomicBool, // no capacity is handed out or made available
+    idgen: AtomicU64, // id generator, skips IDs of live parts once it wraps
+    throttled: AtomicU64, // number of `get` calls which failed with `Error::NoCapacity`
+    refill: Option<Refill>, // rate of continuous refills, if the algorithm accrues capacity
+    decay: Option<Decay>, // decay of the weight of idle parts
     capacity: Mutex<Capacity>,
 }
 
+// The capacity is formatted only if its lock is free, so that a bucket can
+// be logged even while the lock is held, without blocking.
+impl fmt::Debug for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("Bucket");
+        s.field("maximum", &self.maximum())
+            .field("max_parts", &self.max_parts)
+            .field("ramp", &self.ramp)
+            .field("fair", &self.fair)
+            .field("paused", &self.paused)
+            .field("idgen", &self.idgen)
+            .field("throttled", &self.throttled)
+            .field("refill", &self.refill)
+            .field("decay", &self.decay);
+        match self.capacity.try_lock() {
+            Some(cap) => s.field("capacity", &*cap),
+            None => s.field("capacity", &format_args!("<locked>"))
+        };
+        s.finish()
+    }
+}
+
+/// The weight level of a part which is not decayed.
+const FULL: u64 = 1024;
+
+/// The decay of the weight of parts which have been idle during a time
+/// index, in units of `FULL`.
+#[derive(Clone, Copy, Debug)]
+pub struct Decay {
+    factor: u64, // fraction of the level kept per idle time index
+    floor: u64, // lowest level
+}
+
+impl Decay {
+    /// Keep `factor` of the weight per idle time index, down to `floor`
+    /// of the weight. Both are clamped to [0, 1], i.e. with a `factor` of
+    /// 0, the weight drops to the floor after a single idle time index.
+    /// The floor is at least 1/1024 of the weight, so that idle parts are
+    /// never left out of the division entirely.
+    pub fn new(factor: f64, floor: f64) -> Decay {
+        let level = |x: f64| (x.clamp(0.0, 1.0) * FULL as f64) as u64;
+        Decay { factor: level(factor), floor: max(1, level(floor)) }
+    }
+}
+
 #[derive(Debug)]
 struct Capacity {
     index: usize, // time index
     value: usize, // capacity value
-    parts: usize, // parts over which to spread the available capacity
+    debt: usize, // quantity overdrawn, to be repaid from future capacity
+    credit: usize, // capacity value at the start of the time index
+    parts: HashMap<Id, Part>, // parts over which to spread the available capacity
+    weight: u64, // sum of all part weights, in units of `FULL`
+    seqno: u64, // counter to order parts which have been turned away
+    total: u64, // quantity handed out (and not given back) since creation
+    served: usize, // quantity handed out during the previous time index
+    departed: usize, // quantity handed out during the current time index to parts removed since
+    cursor: Option<Id>, // part whose turn it is during the current time index
+    extra: HashSet<Id>, // parts whose share is rounded up during the current time index
+    guaranteed: Vec<Id>, // parts with a minimum quantity per time index
+    prioritized: usize, // number of parts with a priority above 0
+    last: Option<Instant>, // time up to which capacity has been refilled continuously
+    algorithm: Arc<dyn RateAlgorithm>, // decides when capacity becomes available
+}
+
+/// The rate at which a bucket's capacity is refilled continuously, if its
+/// algorithm accrues capacity over time.
+#[derive(Clone, Debug)]
+pub struct Refill {
+    pub clock: Arc<dyn Clock>,
+    pub amount: usize, // quantity refilled per `window`
+    pub window: Duration,
+}
+
+/// A consistent view of a bucket's state.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    pub maximum: usize,
+    pub index: usize,
+    pub value: usize,
+    pub parts: usize,
+    pub total: u64,
+    pub served: usize,
+}
+
+/// A view of a part's state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartStats {
+    /// The ID of the part.
+    pub id: Id,
+    /// The tag the part has been registered with, if any.
+    pub tag: Option<u64>,
+    /// The weight of the part.
+    pub weight: u32,
+    /// Whether the part is paused.
+    pub paused: bool,
+    /// The quantity handed out to the part during the current time index.
+    pub used: usize,
+}
+
+impl Capacity {
+    /// If the capacity value is less than the number of parts, decide if
+    /// the given part may take one item. Parts which have been turned away
+    /// before go first, in the order in which they were turned away.
+    fn has_turn(&self, id: Id, value: usize) -> bool {
+        let owed = self.parts.get(&id).and_then(|p| p.owed);
+        let ahead = self.parts.values()
+            .filter(|p| p.weight() > 0)
+            .filter_map(|p| p.owed)
+            .filter(|&n| owed.map_or(true, |m| n < m))
+            .count();
+        ahead < value
+    }
+
+    /// The sum of all guaranteed quantities and the part of it which has
+    /// not been handed out yet during the current time index.
+    fn reserved(&self) -> (usize, usize) {
+        self.guaranteed.iter()
+            .filter_map(|id| self.parts.get(id))
+            .fold((0, 0), |(total, unmet), p| (total + p.floor, unmet + p.unmet(self.index)))
+    }
+
+    /// The quantity up to `hint` the given part may overdraw.
+    fn overdraft(&self, id: Id, hint: usize) -> usize {
+        match self.parts.get(&id) {
+            Some(p) if p.weight() > 0 => min(hint, p.overdraft.saturating_sub(self.debt)),
+            _ => 0
+        }
+    }
+
+    /// Repay the debt from the capacity value, as far as possible.
+    fn repay(&mut self) {
+        let n = min(self.debt, self.value);
+        self.debt -= n;
+        self.value -= n
+    }
+
+    /// The quantity expected to be taken by parts of a higher priority than
+    /// the given one during the current time index, the part of it which
+    /// has not been handed out yet, and the total weight of the parts of the
+    /// given priority.
+    fn precedence(&self, priority: u8) -> (usize, usize, u64) {
+        if self.prioritized == 0 {
+            return (0, 0, self.weight)
+        }
+        let mut result = (0usize, 0usize, 0);
+        for p in self.parts.values().filter(|p| p.weight() > 0) {
+            if p.priority > priority {
+                let expected = p.expected(self.index);
+                result.0 = result.0.saturating_add(expected);
+                result.1 = result.1.saturating_add(expected.saturating_sub(p.used(self.index)))
+            } else if p.priority == priority {
+                result.2 += p.weight()
+            }
+        }
+        result
+    }
+}
+
+/// The outcome of `Bucket::grant`.
+struct Grant {
+    quant: usize, // the quantity to hand out
+    share: usize, // the part's share of the remaining capacity
+    starved: bool, // the part is turned away because others go first
+}
+
+#[derive(Debug)]
+struct Part {
+    weight: u32, // relative share of the capacity
+    paused: bool, // paused parts are not considered in the division
+    closed: bool, // closed parts do not get any capacity
+    cap: Option<usize>, // maximum quantity per time index
+    floor: usize, // guaranteed quantity per time index
+    index: usize, // time index of `used`
+    used: usize, // quantity handed out during `index`
+    owed: Option<u64>, // set if the part has been turned away without getting anything
+    born: usize, // time index at which the part has been added
+    tag: Option<u64>, // external tag of the part
+    level: u64, // fraction of the weight in effect, in units of `FULL`
+    priority: u8, // parts of a higher priority take precedence
+    overdraft: usize, // maximum debt the part may incur once there is no capacity left
+    hungry: bool, // the part got less than it asked for during `index`
+    expected: usize, // quantity the part is expected to take during `index`
+}
+
+impl Part {
+    fn new(born: usize) -> Part {
+        Part {
+            weight: 1,
+            paused: false,
+            closed: false,
+            cap: None,
+            floor: 0,
+            index: 0,
+            used: 0,
+            owed: None,
+            born,
+            tag: None,
+            level: FULL,
+            priority: 0,
+            overdraft: 0,
+            hungry: false,
+            expected: 0
+        }
+    }
+
+    /// The weight with which this part participates in the division, in
+    /// units of `FULL`.
+    fn weight(&self) -> u64 {
+        if self.paused || self.closed { 0 } else { u64::from(self.weight) * self.level }
+    }
+
+    /// The guaranteed quantity not yet handed out during the given time index.
+    fn unmet(&self, index: usize) -> usize {
+        if self.weight() > 0 { self.floor.saturating_sub(self.used(index)) } else { 0 }
+    }
+
+    /// The quantity handed out to this part during the given time index.
+    fn used(&self, index: usize) -> usize {
+        if self.index == index { self.used } else { 0 }
+    }
+
+    /// The quantity this part is expected to take during the given time
+    /// index, based on the previous time index: all it can get if it got
+    /// less than it asked for, otherwise as much as it took.
+    fn expected(&self, index: usize) -> usize {
+        match index.checked_sub(self.index) {
+            Some(0) => self.expected,
+            Some(1) if self.hungry => self.cap.unwrap_or(usize::MAX),
+            Some(1) => self.used,
+            _ => 0
+        }
+    }
+
+    /// Move on to the given time index.
+    fn roll(&mut self, index: usize) {
+        if self.index != index {
+            self.expected = self.expected(index);
+            self.index = index;
+            self.used = 0;
+            self.hungry = false
+        }
+    }
 }
 
 impl Bucket {
     /// Create a new bucket with the given maximum capacity.
     pub fn new(capacity: usize) -> Bucket {
         Bucket {
-            maximum: capacity,
-            idgen: AtomicUsize::new(1),
+            maximum: AtomicUsize::new(capacity),
+            max_parts: AtomicUsize::new(usize::MAX),
+            ramp: 0,
+            fair: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            idgen: AtomicU64::new(1),
+            throttled: AtomicU64::new(0),
+            refill: None,
+            decay: None,
             capacity: Mutex::new(Capacity {
                 index: 0,
                 value: capacity,
-                parts: 0,
+                debt: 0,
+                credit: capacity,
+                parts: HashMap::new(),
+                weight: 0,
+                seqno: 0,
+                total: 0,
+                served: 0,
+                departed: 0,
+                cursor: None,
+                extra: HashSet::new(),
+                guaranteed: Vec::new(),
+                prioritized: 0,
+                last: None,
+                algorithm: Arc::new(TokenBucket),
             }),
         }
     }
 
     /// Get a `Token` which contains as quantity the number of items of
     /// the remaining capacity divided by parts.
-    pub fn get(&self, _id: Id, hint: usize) -> Result<Token> {
-        let mut cap = self.capacity.lock();
+    ///
+    /// If the remaining capacity is too small to give every part at least
+    /// one item, parts which have been turned away before take precedence,
+    /// so that over successive time indices every part makes progress.
+    pub fn get(&self, id: Id, hint: usize) -> Result<Token> {
+        let mut guard = self.capacity.lock();
+        let cap = &mut *guard;
+        self.accrue(cap);
+
+        if cap.parts.get(&id).is_some_and(|p| p.closed) {
+            return Err(Error::Closed);
+        }
+
+        // paused => never any capacity
+        if self.is_paused() {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::NoCapacity);
+        }
 
         // no parts => always at full capacity
-        if cap.parts == 0 {
-            return Ok(Token::new(cap.index, self.maximum));
+        if cap.parts.is_empty() {
+            return Ok(Token::new(id, cap.index, self.maximum()));
         }
 
-        let quant = match cap.value / cap.parts {
-            0 if cap.value > 0 => 1,
-            x => min(x, hint),
-        };
+        // a part which has been idle recovers its weight right away
+        let index = cap.index;
+        if let Some(part) = cap.parts.get_mut(&id) {
+            let old = part.weight();
+            part.level = FULL;
+            part.roll(index);
+            cap.weight = cap.weight - old + part.weight()
+        }
+
+        let Grant { quant, share, starved } = self.grant(cap, id, hint);
+
+        // parts which may overdraw borrow from future capacity
+        let debt = if quant == 0 { cap.overdraft(id, hint) } else { 0 };
+        let quant = quant + debt;
+
+        if let Some(part) = cap.parts.get_mut(&id) {
+            part.hungry |= quant < hint;
+            if quant > 0 {
+                part.used += quant;
+                part.owed = None
+            } else if starved && part.owed.is_none() && part.used == 0 {
+                cap.seqno += 1;
+                part.owed = Some(cap.seqno)
+            }
+        }
 
         if quant == 0 {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
             return Err(Error::NoCapacity);
         }
 
-        cap.value -= quant;
-        let t = Token::new(cap.index, quant);
-        MutexGuard::unlock_fair(cap);
+        cap.value -= quant - debt;
+        cap.debt += debt;
+        cap.total += quant as u64;
+        let mut t = Token::new(id, cap.index, quant);
+        t.remnant = share == 0 && debt == 0;
+        if self.fair.load(Ordering::Relaxed) {
+            MutexGuard::unlock_fair(guard)
+        } else {
+            drop(guard)
+        }
         Ok(t)
     }
 
+    /// Why `get` does not hand out anything to the given part, assuming
+    /// it does not.
+    pub fn throttle_reason(&self, id: Id) -> ThrottleReason {
+        let cap = self.capacity.lock();
+        let part = cap.parts.get(&id);
+        if self.is_paused() || part.is_some_and(|p| p.weight() == 0) {
+            return ThrottleReason::Paused
+        }
+        if let Some(p) = part {
+            if p.cap.is_some_and(|max| p.used(cap.index) >= max) {
+                return ThrottleReason::Capped
+            }
+        }
+        let (_, unmet) = cap.reserved();
+        let own = part.map_or(0, |p| p.unmet(cap.index));
+        if cap.value > 0 && cap.value <= unmet - own {
+            return ThrottleReason::Reserved
+        }
+        ThrottleReason::NoCapacity
+    }
+
+    /// Get a `Token` of the maximum capacity for the given part, without
+    /// taking it from the remaining capacity.
+    pub fn full(&self, id: Id) -> Token {
+        Token::new(id, self.capacity.lock().index, self.maximum())
+    }
+
+    /// The quantity `get` would currently hand out to the given part,
+    /// without actually handing it out.
+    pub fn peek(&self, id: Id) -> Result<usize> {
+        let mut cap = self.capacity.lock();
+        self.accrue(&mut cap);
+        if cap.parts.get(&id).is_some_and(|p| p.closed) {
+            return Err(Error::Closed)
+        }
+        if self.is_paused() {
+            return Ok(0)
+        }
+        if cap.parts.is_empty() {
+            return Ok(self.maximum())
+        }
+        match self.grant(&cap, id, usize::MAX).quant {
+            0 => Ok(cap.overdraft(id, usize::MAX)),
+            n => Ok(n)
+        }
+    }
+
+    /// Determine the quantity to hand out to the given part.
+    fn grant(&self, cap: &Capacity, id: Id, hint: usize) -> Grant {
+        let weight = cap.parts.get(&id).map_or(FULL, Part::weight);
+        // parts of a higher priority take what they are expected to take
+        // first, the rest is divided among the parts of the same priority
+        let priority = cap.parts.get(&id).map_or(0, |p| p.priority);
+        let (ahead, pending, level) = cap.precedence(priority);
+        let total = u128::from(max(1, level));
+        // guaranteed quantities are set aside before dividing the rest
+        let (floors, unmet) = cap.reserved();
+        let own = cap.parts.get(&id).map_or(0, |p| p.unmet(cap.index));
+        let value = cap.value.saturating_sub(unmet).saturating_sub(pending);
+        let mut share = value as u128 * u128::from(weight) / total;
+        if cap.parts.get(&id).is_some_and(|p| p.used(cap.index) == 0) {
+            // first token of this time index, unless others took too much
+            // already; what removed parts took is no longer divided among
+            // the remaining parts
+            let credit = cap.credit
+                .saturating_sub(cap.departed)
+                .saturating_sub(floors)
+                .saturating_sub(ahead) as u128 * u128::from(weight);
+            let first = if cap.extra.contains(&id) { credit.div_ceil(total) } else { credit / total };
+            // if the capacity is less than the parts, `has_turn` decides
+            if credit >= total && first <= value as u128 {
+                share = max(share, first)
+            }
+        }
+        let mut quant = match share as usize {
+            0 if value > 0 && weight > 0 && cap.has_turn(id, value) => 1,
+            x => x,
+        };
+        quant = min(quant + own, hint);
+        let starved = share == 0 && weight > 0 && quant == 0;
+        if let Some(part) = cap.parts.get(&id) {
+            if let Some(max) = part.cap {
+                quant = min(quant, max.saturating_sub(part.used(cap.index)))
+            }
+            let age = cap.index.saturating_sub(part.born) + 1;
+            if age < self.ramp {
+                let limit = max(1, cap.credit * age / self.ramp);
+                quant = min(quant, limit.saturating_sub(part.used(cap.index)))
+            }
+        }
+        Grant { quant, share: share as usize, starved }
+    }
+
     /// Give back the reviously retrieved `Token` which increases available
     /// capacity. Tokens which have expired will not be considered.
-    pub fn release(&self, t: Token) {
+    pub fn release(&self, mut t: Token) {
+        let quant = std::mem::take(&mut t.quant);
+        self.give_back(t.id, t.index, quant)
+    }
+
+    /// Give back the given quantity handed out to a part during the given
+    /// time index.
+    pub(crate) fn give_back(&self, id: Id, index: usize, quant: usize) {
         let mut cap = self.capacity.lock();
-        if t.index == cap.index {
-            cap.value += t.get()
+        if index == cap.index {
+            cap.value += quant;
+            cap.repay();
+            cap.total = cap.total.saturating_sub(quant as u64);
+            match cap.parts.get_mut(&id) {
+                Some(part) => if part.index == index {
+                    part.used = part.used.saturating_sub(quant)
+                }
+                None => cap.departed = cap.departed.saturating_sub(quant)
+            }
         }
     }
 
-    /// Reset the time index and make the maximum capacity available again.
-    pub fn reset(&self, i: usize) {
+    /// Reset the time index and make the given capacity available, which
+    /// is at most the maximum capacity, as far as the algorithm does so at
+    /// the start of a time index. While the bucket is paused, no capacity
+    /// is made available. Any debt is repaid from the capacity first.
+    ///
+    /// Returns the quantity handed out during the previous time index.
+    pub fn reset(&self, i: usize, value: usize) -> usize {
         let mut cap = self.capacity.lock();
+        self.accrue(&mut cap);
+        let served = cap.credit.saturating_sub(cap.value);
+        cap.served = served;
+        if let Some(d) = self.decay {
+            decay(&mut cap, d)
+        }
         cap.index = i;
-        cap.value = self.maximum
+        if self.is_paused() {
+            cap.value = 0
+        } else {
+            cap.value = cap.algorithm.start(cap.value, min(value, self.maximum()))
+        }
+        cap.repay();
+        cap.credit = cap.value;
+        cap.departed = 0;
+        cap.cursor = next_turn(&cap);
+        cap.extra = extra_shares(&cap);
+        served
+    }
+
+    /// Add the capacity refilled continuously since the last time, if the
+    /// algorithm accrues capacity over time.
+    fn accrue(&self, cap: &mut Capacity) {
+        let (refill, depth) = match (&self.refill, cap.algorithm.depth(self.maximum())) {
+            (Some(r), Some(d)) => (r, d),
+            _ => return
+        };
+        let now = refill.clock.now();
+        if self.is_paused() {
+            // nothing accumulates while paused
+            cap.last = Some(now);
+            return
+        }
+        let last = *cap.last.get_or_insert(now);
+        let window = refill.window.as_nanos();
+        let quant = refill.amount as u128 * now.saturating_duration_since(last).as_nanos() / window;
+        let room = depth.saturating_sub(cap.value);
+        if quant >= room as u128 {
+            cap.value = max(depth, cap.value);
+            cap.credit += room;
+            cap.last = Some(now)
+        } else if quant > 0 {
+            cap.value += quant as usize;
+            cap.credit += quant as usize;
+            // keep the time of fractions not yet credited
+            let nanos = quant * window / refill.amount as u128;
+            cap.last = Some(last + Duration::from_nanos(nanos as u64))
+        }
+        cap.repay()
+    }
+
+    /// Get the current state, collected under a single lock acquisition.
+    pub fn stats(&self) -> Stats {
+        let mut cap = self.capacity.lock();
+        self.accrue(&mut cap);
+        Stats {
+            maximum: self.maximum(),
+            index: cap.index,
+            value: if self.is_paused() { 0 } else { cap.value },
+            parts: cap.parts.len(),
+            total: cap.total,
+            served: cap.served,
+        }
+    }
+
+    /// Let parts get at most `age / ramp` of the capacity of a time index
+    /// during the first `ramp` time indices after they have been added.
+    pub fn with_soft_start(mut self, ramp: usize) -> Bucket {
+        self.ramp = ramp;
+        self
+    }
+
+    /// Let the weight of parts which do not call `get` during a time index
+    /// decay, so that active parts get more of the capacity. A part's weight
+    /// is restored as soon as it calls `get` again.
+    pub fn with_idle_decay(mut self, decay: Decay) -> Bucket {
+        self.decay = Some(decay);
+        self
+    }
+
+    /// The decay of the weight of idle parts, if any.
+    pub fn idle_decay(&self) -> Option<Decay> {
+        self.decay
+    }
+
+    /// Refill the capacity continuously at the given rate, if the
+    /// algorithm accrues capacity over time.
+    pub fn with_refill(mut self, refill: Refill) -> Bucket {
+        self.refill = Some(refill);
+        self
+    }
+
+    /// Use the given algorithm to decide when capacity becomes available.
+    pub fn with_algorithm(self, algorithm: Arc<dyn RateAlgorithm>) -> Bucket {
+        self.set_algorithm(algorithm);
+        self
+    }
+
+    /// Switch to the given algorithm. Parts and the time index are left as
+    /// they are, so tokens handed out before can be given back as usual.
+    /// Capacity which has accrued until now is added first; from now on,
+    /// the capacity accrues as the new algorithm decides. Capacity beyond
+    /// the depth of the new algorithm is dropped.
+    pub fn set_algorithm(&self, algorithm: Arc<dyn RateAlgorithm>) {
+        let mut cap = self.capacity.lock();
+        self.accrue(&mut cap);
+        if let Some(depth) = algorithm.depth(self.maximum()) {
+            let excess = cap.value.saturating_sub(depth);
+            cap.value -= excess;
+            cap.credit = cap.credit.saturating_sub(excess)
+        }
+        cap.algorithm = algorithm;
+        cap.last = None
+    }
+
+    /// The algorithm which decides when capacity becomes available.
+    pub fn algorithm(&self) -> Arc<dyn RateAlgorithm> {
+        self.capacity.lock().algorithm.clone()
+    }
+
+    /// Enable or disable fair unlocking in `get`, which prevents threads
+    /// from being starved of the lock at the expense of throughput. It is
+    /// meant to be enabled while tasks are waiting for capacity.
+    pub fn set_fair(&self, fair: bool) {
+        self.fair.store(fair, Ordering::Relaxed)
+    }
+
+    /// Whether `get` unlocks fairly.
+    #[cfg(test)]
+    pub(crate) fn is_fair(&self) -> bool {
+        self.fair.load(Ordering::Relaxed)
+    }
+
+    /// Stop handing out capacity to all parts until `resume` is called.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed)
+    }
+
+    /// Hand out capacity again after `pause`. Capacity which has not been
+    /// made available while paused is not made up for.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed)
+    }
+
+    /// Whether the bucket is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Preallocate space for the given number of parts.
+    pub fn with_expected_parts(mut self, n: usize) -> Bucket {
+        let cap = self.capacity.get_mut();
+        cap.parts.reserve(n);
+        cap.extra.reserve(n);
+        self
+    }
+
+    /// Allow at most `max_parts` parts to be added.
+    pub fn with_max_parts(mut self, max_parts: usize) -> Bucket {
+        *self.max_parts.get_mut() = max_parts;
+        self
+    }
+
+    /// Change the maximum number of parts. If there are more parts already,
+    /// no new parts can be added until enough parts have been removed.
+    pub fn set_max_parts(&self, max_parts: usize) {
+        self.max_parts.store(max_parts, Ordering::Relaxed)
+    }
+
+    /// The maximum number of parts.
+    pub fn max_parts(&self) -> usize {
+        self.max_parts.load(Ordering::Relaxed)
+    }
+
+    /// Change the maximum capacity, which takes effect on the next `reset`.
+    pub fn set_maximum(&self, maximum: usize) {
+        self.maximum.store(maximum, Ordering::Relaxed)
+    }
+
+    /// The maximum capacity.
+    pub fn maximum(&self) -> usize {
+        self.maximum.load(Ordering::Relaxed)
+    }
+
+    /// The number of time indices over which new parts ramp up to their full share.
+    pub fn soft_start(&self) -> usize {
+        self.ramp
+    }
+
+    /// The number of `get` calls which failed with `Error::NoCapacity`.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Set the total quantity handed out and the number of `get` calls which
+    /// failed with `Error::NoCapacity` back to zero.
+    pub fn reset_stats(&self) {
+        let mut cap = self.capacity.lock();
+        cap.total = 0;
+        self.throttled.store(0, Ordering::Relaxed)
+    }
+
+    /// Run `f` while the capacity lock is held.
+    #[cfg(test)]
+    pub(crate) fn with_lock_held<R>(&self, f: impl FnOnce() -> R) -> R {
+        let _guard = self.capacity.lock();
+        f()
+    }
+
+    /// The current number of parts.
+    pub fn parts(&self) -> usize {
+        self.capacity.lock().parts.len()
     }
 
     /// Attempt to increase the number of parts by one.
-    /// This can fail if it would result in more parts than the maximum capacity.
+    /// This fails with `Error::TooManyParts` if it would result in more
+    /// than the maximum number of parts.
     pub fn add_part(&self) -> Result<Id> {
         let mut cap = self.capacity.lock();
-        if cap.parts >= self.maximum {
-            return Err(Error::NoCapacity);
+        if cap.parts.len() >= self.max_parts() {
+            return Err(Error::TooManyParts);
+        }
+        let id = self.unused_id(&cap);
+        let part = Part::new(cap.index);
+        cap.weight += part.weight();
+        cap.parts.insert(id, part);
+        Ok(id)
+    }
+
+    /// Generate a new ID, which is not used by any part.
+    pub fn new_id(&self) -> Id {
+        self.unused_id(&self.capacity.lock())
+    }
+
+    /// Generate the next ID which is not used by any part. IDs are only
+    /// generated again once the generator has wrapped around.
+    fn unused_id(&self, cap: &Capacity) -> Id {
+        loop {
+            let id = Id(self.idgen.fetch_add(1, Ordering::Relaxed));
+            if !cap.parts.contains_key(&id) {
+                return id
+            }
+        }
+    }
+
+    /// Increase the number of parts by `n` at once. This fails with
+    /// `Error::TooManyParts`, without adding any part, if it would result
+    /// in more than the maximum number of parts.
+    pub fn add_parts(&self, n: usize) -> Result<Vec<Id>> {
+        let mut cap = self.capacity.lock();
+        if cap.parts.len().saturating_add(n) > self.max_parts() {
+            return Err(Error::TooManyParts);
+        }
+        let index = cap.index;
+        let mut ids = Vec::with_capacity(n);
+        for _ in 0 .. n {
+            let id = self.unused_id(&cap);
+            cap.parts.insert(id, Part::new(index));
+            ids.push(id)
+        }
+        cap.weight += n as u64 * FULL;
+        Ok(ids)
+    }
+
+    /// Limit the quantity handed out to the given part per time index.
+    pub fn set_cap(&self, id: Id, max: Option<usize>) {
+        self.update_part(id, |part| part.cap = max)
+    }
+
+    /// Change the weight of the given part. A part with weight 0 does not
+    /// get any capacity.
+    pub fn set_weight(&self, id: Id, weight: u32) {
+        self.update_part(id, |part| part.weight = weight)
+    }
+
+    /// Set the priority of the given part. Parts of a higher priority take
+    /// precedence over all parts of a lower priority: the latter only get
+    /// what the former are not expected to take during a time index, based
+    /// on what they took during the previous one.
+    pub fn set_priority(&self, id: Id, priority: u8) {
+        let cap = &mut *self.capacity.lock();
+        if let Some(part) = cap.parts.get_mut(&id) {
+            match (part.priority > 0, priority > 0) {
+                (false, true) => cap.prioritized += 1,
+                (true, false) => cap.prioritized -= 1,
+                _ => {}
+            }
+            part.priority = priority
+        }
+    }
+
+    /// Let the given part overdraw the capacity by up to `max` once there
+    /// is no capacity left for it. The debt is repaid from the capacity of
+    /// the following time indices before anything else is handed out.
+    pub fn set_overdraft(&self, id: Id, max: usize) {
+        self.update_part(id, |part| part.overdraft = max)
+    }
+
+    /// Pause or resume the given part. Paused parts do not get any capacity
+    /// and are excluded from the division of capacity among parts.
+    pub fn set_paused(&self, id: Id, paused: bool) {
+        self.update_part(id, |part| part.paused = paused)
+    }
+
+    /// Close the given part. Closed parts do not get any capacity and are
+    /// excluded from the division of capacity among parts, but tokens
+    /// handed out before can still be given back.
+    pub fn close(&self, id: Id) {
+        self.update_part(id, |part| part.closed = true)
+    }
+
+    /// Guarantee the given part a minimum quantity per time index, which is
+    /// set aside before the rest of the capacity is divided among parts.
+    /// This fails with `Error::Overcommitted` if the guarantees of all parts
+    /// would exceed the maximum capacity.
+    pub fn set_floor(&self, id: Id, floor: usize) -> Result<()> {
+        let cap = &mut *self.capacity.lock();
+        let others = cap.guaranteed.iter()
+            .filter(|&&g| g != id)
+            .filter_map(|g| cap.parts.get(g))
+            .map(|p| p.floor)
+            .sum::<usize>();
+        if others + floor > self.maximum() {
+            return Err(Error::Overcommitted)
+        }
+        if let Some(part) = cap.parts.get_mut(&id) {
+            part.floor = floor;
+            cap.guaranteed.retain(|&g| g != id);
+            if floor > 0 {
+                cap.guaranteed.push(id)
+            }
+        }
+        Ok(())
+    }
+
+    /// Associate an external tag with the given part.
+    pub fn set_tag(&self, id: Id, tag: u64) {
+        self.update_part(id, |part| part.tag = Some(tag))
+    }
+
+    /// The external tag of the given part, if any.
+    pub fn tag(&self, id: Id) -> Option<u64> {
+        self.capacity.lock().parts.get(&id).and_then(|p| p.tag)
+    }
+
+    /// Get the state of the given part.
+    pub fn part_stats(&self, id: Id) -> Option<PartStats> {
+        let cap = self.capacity.lock();
+        cap.parts.get(&id).map(|p| PartStats {
+            id,
+            tag: p.tag,
+            weight: p.weight,
+            paused: p.paused,
+            used: p.used(cap.index),
+        })
+    }
+
+    /// Apply the given update to a part and keep the total weight consistent.
+    fn update_part<F: FnOnce(&mut Part)>(&self, id: Id, f: F) {
+        let cap = &mut *self.capacity.lock();
+        if let Some(part) = cap.parts.get_mut(&id) {
+            let old = part.weight();
+            f(part);
+            cap.weight = cap.weight - old + part.weight()
         }
-        cap.parts += 1;
-        Ok(Id(self.idgen.fetch_add(1, Ordering::Relaxed)))
     }
 
     /// Remove a previously added part again.
-    pub fn remove_part(&self, _id: Id) {
+    ///
+    /// Returns `false` without any effect if there is no part with the
+    /// given ID, e.g. because it has been removed already.
+    pub fn remove_part(&self, id: Id) -> bool {
         let mut cap = self.capacity.lock();
-        if cap.parts > 0 {
-            cap.parts -= 1
+        if let Some(part) = cap.parts.remove(&id) {
+            cap.weight -= part.weight();
+            cap.departed += part.used(cap.index);
+            if part.floor > 0 {
+                cap.guaranteed.retain(|&g| g != id)
+            }
+            if part.priority > 0 {
+                cap.prioritized -= 1
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Let the weight of all parts which have not been handed out anything
+/// during the current time index decay.
+fn decay(cap: &mut Capacity, d: Decay) {
+    let index = cap.index;
+    for p in cap.parts.values_mut().filter(|p| p.used(index) == 0 && p.level > d.floor) {
+        let old = p.weight();
+        p.level = max(d.floor, p.level * d.factor / FULL);
+        cap.weight = cap.weight - old + p.weight()
+    }
+}
+
+/// The parts whose shares are rounded up to hand out the remainder of the
+/// division of the capacity, in round-robin order of IDs from the cursor.
+fn extra_shares(cap: &Capacity) -> HashSet<Id> {
+    let (floors, _) = cap.reserved();
+    let credit = cap.credit.saturating_sub(floors) as u128;
+    let total = u128::from(max(1, cap.weight));
+    let mut active = cap.parts.iter()
+        .filter(|(_, p)| p.weight() > 0)
+        .map(|(id, p)| (*id, u128::from(p.weight())))
+        .collect::<Vec<_>>();
+    active.sort_unstable();
+    let start = active.iter().position(|(id, _)| Some(*id) == cap.cursor).unwrap_or(0);
+    active.rotate_left(start);
+    let shares = active.iter().map(|(_, w)| credit * w / total).sum::<u128>();
+    let remainder = credit.saturating_sub(shares) as usize;
+    active.into_iter()
+        .filter(|(_, w)| (credit * w) % total != 0)
+        .take(remainder)
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/// The part following the current cursor in round-robin order of IDs.
+fn next_turn(cap: &Capacity) -> Option<Id> {
+    let active = || cap.parts.iter().filter(|(_, p)| p.weight() > 0).map(|(id, _)| *id);
+    active()
+        .filter(|&id| cap.cursor.map_or(true, |c| id > c))
+        .min()
+        .or_else(|| active().min())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_of_live_parts_are_not_reused() {
+        let bucket = Bucket::new(100);
+        let live = bucket.add_parts(2).unwrap();
+        assert_eq!(vec![Id(1), Id(2)], live);
+        bucket.idgen.store(u64::MAX - 1, Ordering::Relaxed);
+        let a = bucket.add_part().unwrap();
+        let more = bucket.add_parts(3).unwrap();
+        let b = bucket.new_id();
+        assert_eq!(Id(u64::MAX - 1), a);
+        assert_eq!(vec![Id(u64::MAX), Id(0), Id(3)], more);
+        assert_eq!(Id(4), b);
+        assert_eq!(6, bucket.parts())
+    }
+
+    #[test]
+    fn every_part_progresses_if_capacity_is_less_than_parts() {
+        let parts = 10;
+        let bucket = Bucket::new(parts);
+        let ids = (0 .. parts).map(|_| bucket.add_part().unwrap()).collect::<Vec<_>>();
+        let mut served = vec![0; parts];
+        for i in 1 ..= parts {
+            bucket.reset(i, 3);
+            // parts always ask in the same order and take all they can get
+            for (k, id) in ids.iter().enumerate() {
+                while let Ok(t) = bucket.get(*id, 100) {
+                    served[k] += t.get()
+                }
+            }
+        }
+        assert!(served.iter().all(|&n| n > 0), "served = {:?}", served);
+        assert_eq!(3 * parts, served.iter().sum::<usize>())
+    }
+
+    #[test]
+    fn parts_take_turns_at_the_remainder() {
+        let bucket = Bucket::new(100);
+        let ids = (0 .. 3).map(|_| bucket.add_part().unwrap()).collect::<Vec<_>>();
+        let mut served = vec![0; ids.len()];
+        for i in 1 ..= 30 {
+            bucket.reset(i, 100);
+            // parts always ask in the same order, once per time index
+            for (k, id) in ids.iter().enumerate() {
+                served[k] += bucket.get(*id, 100).unwrap().get()
+            }
+        }
+        assert_eq!(vec![1000; 3], served)
+    }
+
+    #[test]
+    fn remainder_is_handed_out() {
+        let bucket = Bucket::new(99);
+        let ids = (0 .. 10).map(|_| bucket.add_part().unwrap()).collect::<Vec<_>>();
+        for i in 1 ..= 10 {
+            bucket.reset(i, 99);
+            // every part asks once per time index
+            let served = ids.iter().map(|id| bucket.get(*id, 100).unwrap().get()).sum::<usize>();
+            assert_eq!(99, served)
+        }
+    }
+
+    #[test]
+    fn parts_are_removed_only_once() {
+        let bucket = Bucket::new(90);
+        let ids = (0 .. 3).map(|_| bucket.add_part().unwrap()).collect::<Vec<_>>();
+        assert!(bucket.remove_part(ids[0]));
+        assert!(!bucket.remove_part(ids[0]));
+        assert_eq!(2, bucket.parts());
+        // the remaining parts split the capacity between the two of them
+        assert_eq!(45, bucket.get(ids[1], 100).unwrap().get());
+        assert_eq!(45, bucket.get(ids[2], 100).unwrap().get())
+    }
+
+    #[test]
+    fn removed_parts_do_not_inflate_shares() {
+        let bucket = Bucket::new(100);
+        let ids = (0 .. 4).map(|_| bucket.add_part().unwrap()).collect::<Vec<_>>();
+        bucket.reset(1, 100);
+        assert_eq!(25, bucket.get(ids[0], 100).unwrap().get());
+        assert_eq!(25, bucket.get(ids[1], 100).unwrap().get());
+        assert!(bucket.remove_part(ids[0]));
+        assert!(bucket.remove_part(ids[1]));
+        // the remaining parts split what is left of the time index evenly
+        assert_eq!(25, bucket.get(ids[2], 100).unwrap().get());
+        assert_eq!(25, bucket.get(ids[3], 100).unwrap().get());
+        assert!(bucket.get(ids[2], 100).is_err());
+        assert!(bucket.get(ids[3], 100).is_err());
+        for i in 2 .. 5 {
+            bucket.reset(i, 100);
+            let served = ids[2 ..].iter().map(|id| bucket.get(*id, 100).unwrap().get()).sum::<usize>();
+            assert_eq!(100, served)
+        }
+    }
+
+    #[test]
+    fn decay_is_clamped() {
+        let d = Decay::new(0.0, 0.0);
+        assert_eq!((0, 1), (d.factor, d.floor));
+        let d = Decay::new(-1.0, 2.0);
+        assert_eq!((0, FULL), (d.factor, d.floor));
+        // without any factor, an idle part drops to the floor right away
+        let bucket = Bucket::new(1025).with_idle_decay(Decay::new(0.0, 0.0));
+        let a = bucket.add_part().unwrap();
+        let _idle = bucket.add_part().unwrap();
+        assert_eq!(512, bucket.get(a, 2000).unwrap().get());
+        bucket.reset(1, 1025);
+        assert_eq!(1024, bucket.get(a, 2000).unwrap().get())
+    }
+
+    #[test]
+    fn expected_parts_are_preallocated() {
+        let bucket = Bucket::new(100).with_expected_parts(64);
+        let allocated = bucket.capacity.lock().parts.capacity();
+        assert!(allocated >= 64);
+        for i in 0 .. 64 {
+            bucket.add_part().unwrap();
+            bucket.reset(i, 100);
         }
+        assert_eq!(allocated, bucket.capacity.lock().parts.capacity());
     }
 }
@@ -0,0 +1,144 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! A leaky bucket, which paces transfers evenly.
+//!
+//! A `TokenBucket` makes the capacity of a whole time window available at
+//! its start, where it can be drained in a single burst. A leaky bucket
+//! instead has a level which rises with every byte handed out and leaks
+//! out continuously at the rate of the limiter. Bytes are only handed out
+//! while they fit below the depth of the bucket, which is a small fraction
+//! of the capacity of a time window, so transfers are spread across the
+//! window. The rate, the capacity and the ticks of the limiter are still
+//! measured in time windows, like with any other algorithm.
+
+use super::RateAlgorithm;
+use std::cmp::max;
+
+/// A leaky bucket whose depth is `1/n` of the capacity of a time window.
+///
+/// The room left below the level is the capacity available to the parts
+/// of the limiter. As the bucket is only ever that deep, waiting tasks are
+/// notified `n` times per time window, if some room has been made since.
+#[derive(Clone, Copy, Debug)]
+pub struct LeakyBucket {
+    n: u32
+}
+
+impl LeakyBucket {
+    /// The depth of a leaky bucket by default, as a fraction
+    /// `1/DEFAULT_DEPTH` of the capacity of a time window, i.e. with a rate
+    /// of 1000 bytes per second, at most 100 bytes are handed out at once.
+    pub const DEFAULT_DEPTH: u32 = 10;
+
+    /// Create a leaky bucket whose depth is `1/n` of the capacity of a
+    /// time window, and at least one byte.
+    pub fn new(n: u32) -> LeakyBucket {
+        LeakyBucket { n: max(1, n) }
+    }
+}
+
+impl Default for LeakyBucket {
+    /// Create a leaky bucket whose depth is `1/10` of the capacity of a
+    /// time window (see `LeakyBucket::DEFAULT_DEPTH`).
+    fn default() -> LeakyBucket {
+        LeakyBucket::new(LeakyBucket::DEFAULT_DEPTH)
+    }
+}
+
+impl RateAlgorithm for LeakyBucket {
+    // the level keeps leaking out at the same rate across time windows
+    fn start(&self, left: usize, _credit: usize) -> usize {
+        left
+    }
+
+    fn depth(&self, maximum: usize) -> Option<usize> {
+        Some(max(1, maximum / self.n as usize))
+    }
+
+    fn wakeups(&self) -> u32 {
+        self.n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{algorithms::Token, clock::MockClock, limited::{limited_write_all, Limited}, limiter::Limiter};
+    use futures::{executor::{self, NotifyHandle}, Async, Future};
+    use std::{io, sync::Arc, time::{Duration, Instant}};
+    use super::*;
+    use crate::testing::Wakeups;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn capacity_leaks_out_evenly() {
+        let time = MockClock::new();
+        let (lim, mut clock) = Limiter::builder(1000)
+            .algorithm(Box::new(LeakyBucket::default()))
+            .clock(time.clone())
+            .build_with_manual_clock();
+        let id = lim.register().unwrap();
+        assert_eq!(1000, lim.rate());
+        let mut drained = Vec::new();
+        for _ in 0 .. 20 {
+            drained.push(lim.drain_chunks(id).map(Token::consume).sum::<usize>());
+            time.advance(Duration::from_millis(50));
+            clock.catch_up();
+        }
+        // no more than the depth at once, then the rate per second
+        assert_eq!(100, drained[0]);
+        assert!(drained[1 ..].iter().all(|&n| n == 50), "drained = {:?}", drained);
+        assert_eq!(1, lim.tick());
+
+        // nothing beyond the depth accumulates while idle
+        time.advance(Duration::from_secs(5));
+        clock.catch_up();
+        assert_eq!(100, lim.drain_chunks(id).map(Token::consume).sum::<usize>())
+    }
+
+    #[test]
+    fn waiting_tasks_are_notified_between_ticks() {
+        let time = MockClock::new();
+        let (lim, mut clock) = Limiter::builder(1000)
+            .algorithm(Box::new(LeakyBucket::default()))
+            .clock(time.clone())
+            .build_with_manual_clock();
+        let id = lim.register().unwrap();
+        assert_eq!(100, lim.drain_chunks(id).map(Token::consume).sum::<usize>());
+        let wakeups = Arc::new(Wakeups::default());
+        let handle = NotifyHandle::from(wakeups.clone());
+        let mut available = executor::spawn(lim.block_until_available(50));
+        assert_eq!(Async::NotReady, available.poll_future_notify(&handle, 0).unwrap());
+        // no room has been made yet
+        clock.wake();
+        assert_eq!(0, wakeups.count());
+        time.advance(Duration::from_millis(50));
+        clock.wake();
+        assert_eq!(1, wakeups.count());
+        assert_eq!(Async::Ready(()), available.poll_future_notify(&handle, 0).unwrap());
+        assert_eq!(0, lim.tick())
+    }
+
+    #[test]
+    fn leaky_limiter_paces_within_the_second() {
+        let mut rt = Runtime::new().unwrap();
+        let lim = Limiter::leaky(&mut rt.executor(), 1000).unwrap();
+        let w = Limited::new(io::Cursor::new(Vec::new()), lim.clone()).unwrap();
+        let start = Instant::now();
+        rt.block_on(limited_write_all(w, vec![0; 400])).unwrap();
+        // the depth at once, the rest at 1000 bytes per second without
+        // waiting for the next time window
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(250) && elapsed < Duration::from_millis(800), "elapsed = {:?}", elapsed);
+        assert_eq!(400, lim.total_transferred());
+        assert_eq!(0, lim.tick());
+        rt.shutdown_now().wait().unwrap()
+    }
+}
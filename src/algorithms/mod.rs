@@ -8,13 +8,70 @@
 // at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
 // at https://opensource.org/licenses/MIT.
 
-use std::fmt;
+use self::bucket::Bucket;
+use std::{fmt, sync::{Arc, Weak}};
 
 pub mod bucket;
+pub mod leaky;
+
+/// Decides when the capacity of a `Limiter` becomes available.
+///
+/// The capacity, the parts over which it is divided and the tokens handed
+/// out are kept by the limiter, not by the algorithm, so the algorithm can
+/// be changed at runtime with `Limiter::set_algorithm`.
+pub trait RateAlgorithm: fmt::Debug + Send + Sync {
+    /// The capacity available at the start of a time window, given the
+    /// capacity `left` at the end of the previous one and the capacity
+    /// `credit`ed for the new one, which is at most the maximum capacity.
+    fn start(&self, left: usize, credit: usize) -> usize;
+
+    /// The capacity up to which the rate accrues continuously, in
+    /// proportion to the time elapsed, given the maximum capacity of a time
+    /// window. `None` if capacity only becomes available on `start`.
+    fn depth(&self, maximum: usize) -> Option<usize>;
+
+    /// How often per time window tasks waiting for capacity are notified.
+    /// Between the starts of time windows, they are only notified if some
+    /// capacity is available.
+    fn wakeups(&self) -> u32 {
+        1
+    }
+}
+
+/// Makes the capacity of a whole time window available at its start,
+/// where it can be used up in a single burst. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokenBucket;
+
+impl RateAlgorithm for TokenBucket {
+    fn start(&self, _left: usize, credit: usize) -> usize {
+        credit
+    }
+
+    fn depth(&self, _maximum: usize) -> Option<usize> {
+        None
+    }
+}
+
+/// Refills the capacity continuously, in proportion to the time elapsed,
+/// up to the capacity of a whole time window, see
+/// `LimiterBuilder::continuous`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ContinuousRefill;
+
+impl RateAlgorithm for ContinuousRefill {
+    fn start(&self, left: usize, _credit: usize) -> usize {
+        left
+    }
+
+    fn depth(&self, maximum: usize) -> Option<usize> {
+        Some(maximum)
+    }
+}
 
 /// An opaque ID used for registration purposes.
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
-pub struct Id(usize);
+pub struct Id(u64);
 
 impl fmt::Display for Id {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -22,16 +79,62 @@ impl fmt::Display for Id {
     }
 }
 
-/// A Token represents an indexed quantity.
+/// A Token represents an indexed quantity handed out to a part.
+///
+/// Tokens are consumed when released, so they can not be given back twice.
+/// A token acquired from a `Limiter` which is dropped without being
+/// consumed or released gives its quantity back automatically.
+#[derive(Debug)]
+#[must_use = "capacity is reserved until the token is used or released"]
 pub struct Token {
+    id: Id,
     index: usize,
     quant: usize,
+    remnant: bool,
+    bucket: Option<Weak<Bucket>>, // where to give the quantity back to on drop
+    parent: Option<Box<Token>>, // the same quantity, borrowed from a parent limiter
 }
 
 impl Token {
-    /// Create a new token with the given index and quantity
-    fn new(index: usize, quant: usize) -> Token {
-        Token { index, quant }
+    /// Create a new token for the given part with the given index and quantity
+    fn new(id: Id, index: usize, quant: usize) -> Token {
+        Token { id, index, quant, remnant: false, bucket: None, parent: None }
+    }
+
+    /// Give the quantity back to the given bucket if this token is dropped.
+    pub(crate) fn attach(&mut self, bucket: &Arc<Bucket>) {
+        self.bucket = Some(Arc::downgrade(bucket))
+    }
+
+    /// Back this token by the given token of a parent limiter, which is
+    /// reduced and given back together with this token.
+    pub(crate) fn borrowed(&mut self, mut parent: Token) {
+        parent.set(self.quant);
+        self.parent = Some(Box::new(parent))
+    }
+
+    /// Add the quantity of another token of the same part and time index.
+    pub(crate) fn merge(&mut self, mut other: Token) {
+        debug_assert!(self.id == other.id && self.index == other.index);
+        self.quant += std::mem::take(&mut other.quant);
+        if let (Some(p), Some(q)) = (&mut self.parent, other.parent.take()) {
+            p.merge(*q)
+        }
+    }
+
+    /// Use up this token's quantity, which is returned, i.e. do not give it
+    /// back when the token is dropped.
+    pub fn consume(mut self) -> usize {
+        if let Some(p) = self.parent.take() {
+            p.consume();
+        }
+        std::mem::take(&mut self.quant)
+    }
+
+    /// Is this token handed out from a remaining capacity which is too small
+    /// to give every part at least one item?
+    pub fn is_remnant(&self) -> bool {
+        self.remnant
     }
 
     /// Get this token's quantity.
@@ -39,6 +142,19 @@ impl Token {
         self.quant
     }
 
+    /// The time index during which this token has been handed out.
+    pub(crate) fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Reduce this token's quantity to the given value and return a token
+    /// with the rest of the quantity.
+    pub(crate) fn split_off(&mut self, q: usize) -> Token {
+        let rest = self.quant.saturating_sub(q);
+        self.set(q);
+        Token::new(self.id, self.index, rest)
+    }
+
     /// Reduce this token's quantity to the given value.
     ///
     /// If the argument is greater than or equal to the current quantity,
@@ -47,5 +163,18 @@ impl Token {
         if q < self.quant {
             self.quant = q
         }
+        if let Some(p) = &mut self.parent {
+            p.set(q)
+        }
+    }
+}
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        if self.quant > 0 {
+            if let Some(bucket) = self.bucket.take().and_then(|b| b.upgrade()) {
+                bucket.give_back(self.id, self.index, self.quant)
+            }
+        }
     }
 }
@@ -0,0 +1,358 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{
+    algorithms::{bucket::Decay, RateAlgorithm},
+    clock::{Clock, SystemClock},
+    error::Result,
+    limiter::{ClockHandle, Limiter, TickInfo}
+};
+use futures::prelude::*;
+use std::{cmp::min, fmt, io, sync::Arc, time::{Duration, Instant}};
+use tokio_executor::Executor;
+
+/// A builder to configure and create `Limiter`s.
+#[derive(Debug)]
+pub struct LimiterBuilder {
+    pub(crate) amount: usize,
+    pub(crate) window: Duration,
+    pub(crate) on_tick: Option<OnTick>,
+    pub(crate) backoff: u32,
+    pub(crate) soft_start: usize,
+    pub(crate) max_parts: usize,
+    pub(crate) continuous: bool,
+    pub(crate) expected_parts: usize,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) on_timer_failure: TimerFailure,
+    pub(crate) throttle_error_kind: io::ErrorKind,
+    pub(crate) idle_decay: Option<Decay>,
+    pub(crate) coalesce: usize,
+    pub(crate) algorithm: Option<Arc<dyn RateAlgorithm>>,
+}
+
+/// The configuration of a `Limiter`, e.g. to store it alongside other
+/// settings. With the `serde` feature, it can be (de-)serialised.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LimiterConfig {
+    /// The number of bytes per `window`.
+    pub rate: usize,
+    /// The length of the time window over which `rate` bytes are credited.
+    pub window: Duration,
+    /// The maximum number of parts.
+    pub max_parts: usize,
+    /// The number of ticks over which new parts ramp up to their full share.
+    pub soft_start: usize,
+    /// The maximum number of ticks to skip when backing off parts.
+    pub backoff: u32,
+    /// Whether capacity is refilled continuously.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub continuous: bool,
+    /// What happens once the background timer fails.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub on_timer_failure: TimerFailure,
+}
+
+/// A transfer rate in bytes per second.
+///
+/// Rates given in kibibytes or mebibytes saturate at `usize::MAX` bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Rate(usize);
+
+impl Rate {
+    /// A rate of `n` bytes per second.
+    pub fn bytes(n: usize) -> Rate {
+        Rate(n)
+    }
+
+    /// A rate of `n` kibibytes, i.e. `n * 1024` bytes, per second.
+    pub fn kib(n: usize) -> Rate {
+        Rate(n.saturating_mul(1024))
+    }
+
+    /// A rate of `n` mebibytes, i.e. `n * 1024 * 1024` bytes, per second.
+    pub fn mib(n: usize) -> Rate {
+        Rate(n.saturating_mul(1024 * 1024))
+    }
+
+    /// The number of bytes per second.
+    pub fn as_bytes(&self) -> usize {
+        self.0
+    }
+}
+
+impl From<usize> for Rate {
+    fn from(n: usize) -> Rate {
+        Rate::bytes(n)
+    }
+}
+
+/// What a `Limiter` does once its background timer has failed, e.g.
+/// because the runtime it has been spawned onto shut down.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimerFailure {
+    /// Stop limiting, i.e. grant the full capacity on every request, so
+    /// that connectivity is not affected.
+    FailOpen,
+    /// Fail all operations with `Error::TimerError`.
+    #[default]
+    FailClosed,
+}
+
+/// A callback invoked on every tick of the background timer.
+pub(crate) struct OnTick(pub(crate) Box<dyn Fn(TickInfo) + Send>);
+
+impl fmt::Debug for OnTick {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OnTick")
+    }
+}
+
+impl LimiterBuilder {
+    /// Create a new builder which caps the transfer rate to the given
+    /// maximum of bytes per second.
+    pub fn new(max: usize) -> LimiterBuilder {
+        LimiterBuilder {
+            amount: max,
+            window: Duration::from_secs(1),
+            on_tick: None,
+            backoff: 0,
+            soft_start: 0,
+            max_parts: usize::MAX,
+            continuous: false,
+            expected_parts: 0,
+            clock: Arc::new(SystemClock),
+            on_timer_failure: TimerFailure::FailClosed,
+            throttle_error_kind: io::ErrorKind::WouldBlock,
+            idle_decay: None,
+            coalesce: 0,
+            algorithm: None,
+        }
+    }
+
+    /// Create a new builder from the given configuration.
+    ///
+    /// # Panics
+    ///
+    /// If the configured window is zero.
+    pub fn from_config(c: &LimiterConfig) -> LimiterBuilder {
+        LimiterBuilder::new(c.rate)
+            .rate_per(c.rate, c.window)
+            .max_parts(c.max_parts)
+            .soft_start(c.soft_start)
+            .backoff(c.backoff)
+            .continuous(c.continuous)
+            .on_timer_failure(c.on_timer_failure)
+    }
+
+    /// The configuration of this builder. A callback set with `on_tick`,
+    /// the clock, the expected number of parts, the throttle error kind, the
+    /// idle decay, the minimum size of coalesced reads and an algorithm set
+    /// with `algorithm` are not part of it.
+    pub fn config(&self) -> LimiterConfig {
+        LimiterConfig {
+            rate: self.amount,
+            window: self.window,
+            max_parts: self.max_parts,
+            soft_start: self.soft_start,
+            backoff: self.backoff,
+            continuous: self.continuous,
+            on_timer_failure: self.on_timer_failure,
+        }
+    }
+
+    /// Cap the transfer rate to `amount` bytes per `window`.
+    ///
+    /// The background timer ticks at least once per second and credits
+    /// capacity proportionally, e.g. 100 bytes per 10 seconds are made
+    /// available as 10 bytes every second.
+    ///
+    /// # Panics
+    ///
+    /// If `window` is zero.
+    pub fn rate_per(mut self, amount: usize, window: Duration) -> LimiterBuilder {
+        assert!(window > Duration::from_secs(0), "window must not be zero");
+        self.amount = amount;
+        self.window = window;
+        self
+    }
+
+    /// Cap the transfer rate to the given `Rate`, e.g. `Rate::mib(1)`,
+    /// instead of the raw number of bytes per second the builder has been
+    /// created with.
+    pub fn rate(self, rate: Rate) -> LimiterBuilder {
+        self.rate_per(rate.as_bytes(), Duration::from_secs(1))
+    }
+
+    /// Invoke the given callback on every tick of the background timer,
+    /// right after the capacity for the new time window has been made
+    /// available.
+    pub fn on_tick<F>(mut self, f: F) -> LimiterBuilder
+    where
+        F: Fn(TickInfo) + Send + 'static
+    {
+        self.on_tick = Some(OnTick(Box::new(f)));
+        self
+    }
+
+    /// Back off parts which repeatedly fail to get capacity after being
+    /// notified, by skipping up to `max_skips` ticks before notifying them
+    /// again. The number of skipped ticks doubles with every failure.
+    ///
+    /// This reduces the wakeups of persistently starved parts. By default,
+    /// all waiting parts are notified on every tick.
+    pub fn backoff(mut self, max_skips: u32) -> LimiterBuilder {
+        self.backoff = max_skips;
+        self
+    }
+
+    /// Let newly registered parts ramp up to their full share over the given
+    /// number of ticks, i.e. during its n-th time window, a new part gets at
+    /// most n / `ticks` of the window's capacity.
+    ///
+    /// This prevents a part registering on an idle limiter from claiming all
+    /// remaining capacity before other parts register.
+    pub fn soft_start(mut self, ticks: usize) -> LimiterBuilder {
+        self.soft_start = ticks;
+        self
+    }
+
+    /// Limit the number of parts, i.e. concurrent `Limited` resources, to
+    /// `max_parts`. By default, the number of parts is unlimited.
+    ///
+    /// The number of parts is independent of the rate. If there are more
+    /// parts than bytes per time window, every part still makes progress,
+    /// albeit not during every time window.
+    pub fn max_parts(mut self, max_parts: usize) -> LimiterBuilder {
+        self.max_parts = max_parts;
+        self
+    }
+
+    /// Preallocate space for `n` parts, i.e. concurrent `Limited` resources,
+    /// to avoid reallocations while they are registered, e.g. during a surge
+    /// of new connections.
+    pub fn expected_parts(mut self, n: usize) -> LimiterBuilder {
+        self.expected_parts = n;
+        self
+    }
+
+    /// Let the weight of parts which are idle during a time window decay by
+    /// `factor` per time window, down to `floor` of their weight, so that
+    /// active parts get a larger share of the capacity. Unlike pausing or
+    /// deregistering idle parts, this reallocates capacity gradually. A
+    /// part's weight is restored as soon as it requests capacity again.
+    ///
+    /// Both `factor` and `floor` are fractions between 0 and 1, values
+    /// outside of that range are clamped. A `factor` of 0 lets the weight
+    /// drop to the floor after a single idle time window, and the floor is
+    /// at least 1/1024 of the weight.
+    pub fn idle_decay(mut self, factor: f64, floor: f64) -> LimiterBuilder {
+        self.idle_decay = Some(Decay::new(factor, floor));
+        self
+    }
+
+    /// Refill capacity continuously, in proportion to the time elapsed,
+    /// instead of making a time window's capacity available all at once
+    /// when it starts. This avoids bursts followed by stalls if parts
+    /// drain the capacity quickly. Tasks waiting for capacity are still
+    /// notified on every tick. This selects the `ContinuousRefill`
+    /// algorithm instead of the `TokenBucket`, see `Limiter::set_algorithm`.
+    pub fn continuous(mut self, continuous: bool) -> LimiterBuilder {
+        self.continuous = continuous;
+        self
+    }
+
+    /// Use the given algorithm to decide when capacity becomes available,
+    /// e.g. a `LeakyBucket`. This takes precedence over `continuous`.
+    pub fn algorithm(mut self, algo: Box<dyn RateAlgorithm>) -> LimiterBuilder {
+        self.algorithm = Some(Arc::from(algo));
+        self
+    }
+
+    /// Decide what happens once the background timer has failed. By default,
+    /// the limiter fails closed, i.e. all operations fail with
+    /// `Error::TimerError`.
+    pub fn on_timer_failure(mut self, policy: TimerFailure) -> LimiterBuilder {
+        self.on_timer_failure = policy;
+        self
+    }
+
+    /// Use the given kind of I/O error when reads or writes of `Limited`
+    /// resources are throttled, e.g. `Interrupted` for consumers which
+    /// retry on that. By default, `WouldBlock` is used, which is what
+    /// asynchronous consumers expect.
+    pub fn throttle_error_kind(mut self, kind: io::ErrorKind) -> LimiterBuilder {
+        self.throttle_error_kind = kind;
+        self
+    }
+
+    /// Let reads of `Limited` resources accumulate capacity across time
+    /// windows until they can read at least `min_read` bytes (or as much as
+    /// the buffer holds), instead of performing many tiny reads at very low
+    /// rates. Capacity accumulated but not read yet, e.g. because the peer
+    /// sent less, is kept for the next read, so reads still make progress.
+    ///
+    /// Coalescing does not apply to resources which are subject to several
+    /// limiters. By default, reads are not coalesced.
+    pub fn coalesce(mut self, min_read: usize) -> LimiterBuilder {
+        self.coalesce = min_read;
+        self
+    }
+
+    /// Use the given clock to measure the time elapsed for continuous
+    /// refills, for `Limiter::time_to_next_tick` and in a limiter created
+    /// with `build_with_manual_clock`, see `ClockHandle::catch_up`. By
+    /// default, the system clock is used.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> LimiterBuilder {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The period of the background timer.
+    pub(crate) fn interval(&self) -> Duration {
+        min(self.window, Duration::from_secs(1))
+    }
+
+    /// Create the `Limiter` and spawn its background timer onto the given executor.
+    pub fn build<E: Executor>(self, e: &mut E) -> Result<Limiter> {
+        Limiter::from_builder(e, self)
+    }
+
+    /// Create the `Limiter` and its timer future without spawning it. The
+    /// caller is responsible for running the future.
+    pub fn build_unspawned(self) -> (Limiter, impl Future<Item = (), Error = ()>) {
+        Limiter::unspawned(self)
+    }
+
+    /// Create the `Limiter` and the future which drives it by starting a new
+    /// time window on every item of the given stream. The caller is
+    /// responsible for running the future.
+    pub fn build_from_interval<S>(self, stream: S) -> (Limiter, impl Future<Item = (), Error = ()>)
+    where
+        S: Stream<Item = Instant>,
+        S::Error: fmt::Display
+    {
+        Limiter::driven_by(self, stream)
+    }
+
+    /// Create the `Limiter` and spawn a thread which runs its timer, see
+    /// `Limiter::new_threaded`. Fails if the thread can not be spawned.
+    pub fn build_threaded(self) -> Result<Limiter> {
+        Limiter::threaded(self)
+    }
+
+    /// Create the `Limiter` without a background timer. Time only advances
+    /// when `ClockHandle::tick` is called.
+    pub fn build_with_manual_clock(self) -> (Limiter, ClockHandle) {
+        let (limiter, ticker) = Limiter::unclocked(self);
+        (limiter, ClockHandle::new(ticker))
+    }
+}
@@ -0,0 +1,73 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use parking_lot::Mutex;
+use std::{fmt, sync::Arc, time::{Duration, Instant}};
+
+/// A source of the current time.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which only advances when told to, e.g. for tests.
+///
+/// Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct MockClock(Arc<Mutex<Instant>>);
+
+impl MockClock {
+    /// Create a new clock, starting at the current system time.
+    pub fn new() -> MockClock {
+        MockClock(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    /// Advance the time by the given duration.
+    pub fn advance(&self, d: Duration) {
+        *self.0.lock() += d
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.0.lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock_advances_on_demand() {
+        let clock = MockClock::new();
+        let copy = clock.clone();
+        let start = clock.now();
+        assert_eq!(start, clock.now());
+        copy.advance(Duration::from_millis(1500));
+        assert_eq!(Duration::from_millis(1500), clock.now() - start)
+    }
+}
@@ -0,0 +1,179 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! Rate-limited I/O for `std::future` and tokio 1.x.
+//!
+//! `Limited` resources of this module and of the crate root can be parts
+//! of the same `Limiter`.
+
+use crate::{algorithms::{Id, Token}, builder::LimiterBuilder, error::{Error, Result}, limiter::Limiter};
+use std::{cmp::min, io, pin::Pin, task::{ready, Context, Poll}, time::Instant};
+use tokio1::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+impl LimiterBuilder {
+    /// Create the `Limiter` and spawn its background timer onto the current
+    /// tokio 1.x runtime.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a tokio 1.x runtime.
+    pub fn spawn(self) -> Limiter {
+        let interval = self.interval();
+        let (limiter, mut ticker) = Limiter::unclocked(self);
+        tokio1::spawn(async move {
+            let mut next = Instant::now() + interval;
+            loop {
+                let at = ticker.next_wakeup(Instant::now(), next);
+                tokio1::time::sleep_until(at.into()).await;
+                if Instant::now() < next {
+                    ticker.wake();
+                    continue
+                }
+                ticker.tick();
+                // like an `Interval`, catch up with ticks which are late
+                next += interval
+            }
+        });
+        limiter
+    }
+}
+
+/// A rate-limited resource implementing the `AsyncRead` and `AsyncWrite`
+/// traits of tokio 1.x.
+#[derive(Debug)]
+pub struct Limited<T> {
+    id: Id,
+    io: T,
+    lim: Limiter,
+}
+
+impl<T> Limited<T> {
+    /// Create a new rate-limited resource, registered as a new part of the
+    /// given `Limiter`.
+    pub fn new(io: T, lim: Limiter) -> Result<Limited<T>> {
+        let id = lim.register()?;
+        Ok(Limited { id, io, lim })
+    }
+
+    /// Acquire capacity for up to `len` bytes. If there is none, the current
+    /// task is woken once new capacity is available. Reads and writes wait
+    /// in different slots, as they may be polled by different tasks.
+    fn poll_acquire(&self, cx: &mut Context<'_>, slot: usize, len: usize) -> Poll<io::Result<Token>> {
+        match self.lim.acquire(self.id, len) {
+            Ok(t) => Poll::Ready(Ok(t)),
+            Err(Error::NoCapacity) => {
+                self.lim.enqueue_waker(self.id, slot, cx.waker().clone())?;
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e.into()))
+        }
+    }
+
+    /// Give back the unused quantity of the given token.
+    fn release(&self, mut t: Token, used: usize) {
+        let n = t.get();
+        t.set(n - used);
+        self.lim.release(t)
+    }
+}
+
+impl<T> Drop for Limited<T> {
+    fn drop(&mut self) {
+        self.lim.deregister(self.id)
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Limited<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Pin::new(&mut self.io).poll_read(cx, buf)
+        }
+        let this = &mut *self;
+        let t = ready!(this.poll_acquire(cx, 0, buf.remaining()))?;
+        let mut part = buf.take(min(t.get(), buf.remaining()));
+        let result = Pin::new(&mut this.io).poll_read(cx, &mut part);
+        let n = part.filled().len();
+        // `part` has been filled from the start of `buf`'s unfilled memory
+        unsafe { buf.assume_init(n) }
+        buf.advance(n);
+        this.release(t, n);
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Limited<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Pin::new(&mut self.io).poll_write(cx, buf)
+        }
+        let this = &mut *self;
+        let t = ready!(this.poll_acquire(cx, 1, buf.len()))?;
+        let k = min(t.get(), buf.len());
+        let result = Pin::new(&mut this.io).poll_write(cx, &buf[.. k]);
+        let n = match result {
+            Poll::Ready(Ok(n)) => n,
+            _ => 0
+        };
+        this.release(t, n);
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::*;
+    use tokio1::{io::{duplex, AsyncReadExt, AsyncWriteExt}, runtime};
+
+    /// Transfer 500 bytes through a duplex stream, limiting the writing or
+    /// the reading end to 100 bytes per 100 ms, and return the time it took.
+    fn transfer(limit_writer: bool) -> Duration {
+        let rt = runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        rt.block_on(async move {
+            let lim = Limiter::builder(0).rate_per(100, Duration::from_millis(100)).spawn();
+            let (a, b) = duplex(4096);
+            let start = Instant::now();
+            let (mut a, mut b): (Box<dyn AsyncWrite + Unpin + Send>, Box<dyn AsyncRead + Unpin>) =
+                if limit_writer {
+                    (Box::new(Limited::new(a, lim).unwrap()), Box::new(b))
+                } else {
+                    (Box::new(a), Box::new(Limited::new(b, lim).unwrap()))
+                };
+            tokio1::spawn(async move {
+                a.write_all(&[1; 500]).await.unwrap();
+                a.shutdown().await.unwrap()
+            });
+            let mut data = Vec::new();
+            b.read_to_end(&mut data).await.unwrap();
+            assert_eq!(vec![1; 500], data);
+            start.elapsed()
+        })
+    }
+
+    #[test]
+    fn writes_are_rate_limited() {
+        let elapsed = transfer(true);
+        assert!(elapsed >= Duration::from_millis(350), "elapsed = {:?}", elapsed)
+    }
+
+    #[test]
+    fn reads_are_rate_limited() {
+        let elapsed = transfer(false);
+        assert!(elapsed >= Duration::from_millis(350), "elapsed = {:?}", elapsed)
+    }
+}
@@ -14,14 +14,24 @@ use tokio_executor::SpawnError;
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     Io(io::Error),
     Exec(SpawnError),
     NoCapacity,
     TimerError,
-
-    #[doc(hidden)]
-    __Nonexhaustive
+    /// The limiter has as many parts as bytes per time window, so another
+    /// part would not be guaranteed any capacity.
+    TooManyParts,
+    /// The part has been closed and is not granted any more capacity.
+    Closed,
+    /// The guaranteed rates of all parts would exceed the rate of the limiter.
+    Overcommitted,
+    /// The background timer of the limiter is gone, e.g. because the
+    /// executor which ran it has shut down, so no capacity is made
+    /// available any more. Unlike `TimerError`, this is not due to a
+    /// failure of the timer.
+    LimiterShutdown,
 }
 
 impl fmt::Display for Error {
@@ -31,7 +41,10 @@ impl fmt::Display for Error {
             Error::Exec(e) => write!(f, "spawn error: {}", e),
             Error::NoCapacity => f.write_str("no capacity left"),
             Error::TimerError => f.write_str("error executing background timer"),
-            Error::__Nonexhaustive => f.write_str("__Nonexhaustive")
+            Error::TooManyParts => f.write_str("maximum number of parts reached"),
+            Error::Closed => f.write_str("part is closed"),
+            Error::Overcommitted => f.write_str("guaranteed rates exceed the limiter's rate"),
+            Error::LimiterShutdown => f.write_str("background timer has shut down"),
         }
     }
 }
@@ -47,14 +60,77 @@ impl std::error::Error for Error {
 }
 
 
+/// Why a read or write of a rate-limited resource has been throttled.
+///
+/// The I/O error of a throttled read or write carries the reason, which
+/// can be retrieved with `io::Error::get_ref` and downcast, e.g. for
+/// clients which adapt to the cause.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ThrottleReason {
+    /// The capacity of the current time window is exhausted.
+    NoCapacity,
+    /// The limiter or the resource is paused, or the resource's weight is 0.
+    Paused,
+    /// The resource has reached its own cap for the current time window.
+    Capped,
+    /// The capacity left is reserved for resources with a guaranteed rate.
+    Reserved,
+    /// Capacity left is set aside for the other direction of the resource,
+    /// as it has been throttled before.
+    Turn,
+}
+
+impl fmt::Display for ThrottleReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThrottleReason::NoCapacity => f.write_str("no capacity left"),
+            ThrottleReason::Paused => f.write_str("paused"),
+            ThrottleReason::Capped => f.write_str("cap of the resource reached"),
+            ThrottleReason::Reserved => f.write_str("capacity left is reserved for guaranteed rates"),
+            ThrottleReason::Turn => f.write_str("capacity left is set aside for the other direction"),
+        }
+    }
+}
+
+impl std::error::Error for ThrottleReason {}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Error::Io(e)
     }
 }
 
+/// `NoCapacity` maps to `WouldBlock`, `Io` to the wrapped error, and all
+/// other errors to `Other`.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::NoCapacity => io::Error::new(io::ErrorKind::WouldBlock, e),
+            e => io::Error::other(e)
+        }
+    }
+}
+
 impl From<SpawnError> for Error {
     fn from(e: SpawnError) -> Self {
         Error::Exec(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_error_kinds() {
+        let kind = |e: Error| io::Error::from(e).kind();
+        assert_eq!(io::ErrorKind::WouldBlock, kind(Error::NoCapacity));
+        assert_eq!(io::ErrorKind::BrokenPipe, kind(Error::Io(io::ErrorKind::BrokenPipe.into())));
+        assert_eq!(io::ErrorKind::Other, kind(Error::TimerError));
+        assert_eq!(io::ErrorKind::Other, kind(Error::TooManyParts));
+        assert_eq!(io::ErrorKind::Other, kind(Error::Closed));
+        assert_eq!(io::ErrorKind::Other, kind(Error::LimiterShutdown))
+    }
+}
@@ -9,10 +9,29 @@
 // at https://opensource.org/licenses/MIT.
 
 mod algorithms;
+mod builder;
+mod clock;
+#[cfg(feature = "compat")]
+pub mod compat;
 mod error;
 mod limited;
 mod limiter;
+mod oneway;
+mod sink;
+mod tasks;
+#[cfg(test)]
+mod testing;
+mod throttle;
 
-pub use crate::error::Error;
-pub use crate::limited::Limited;
-pub use crate::limiter::Limiter;
+pub use crate::algorithms::{bucket::PartStats, leaky::LeakyBucket, ContinuousRefill, Id, RateAlgorithm, Token, TokenBucket};
+pub use crate::builder::{LimiterBuilder, LimiterConfig, Rate, TimerFailure};
+pub use crate::clock::{Clock, MockClock, SystemClock};
+pub use crate::error::{Error, ThrottleReason};
+pub use crate::limited::{limited_write_all, Limited, LimitedWriteAll};
+pub use crate::limiter::{ClockHandle, Limiter, LimiterSnapshot, Reservation, TickInfo, WeakLimiter};
+pub use crate::oneway::{LimitedReader, LimitedWriter};
+pub use crate::sink::LimitedSink;
+pub use crate::throttle::Throttled;
+
+#[cfg(feature = "wait-stats")]
+pub use crate::limiter::WaitStats;
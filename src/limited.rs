@@ -8,90 +8,1742 @@
 // at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
 // at https://opensource.org/licenses/MIT.
 
-use crate::{algorithms::Id, error::{Error, Result}, limiter::Limiter};
+use crate::{algorithms::{bucket::PartStats, Id, Token}, error::{Error, Result, ThrottleReason}, limiter::Limiter};
 use futures::prelude::*;
-use std::{cmp::min, io};
+use std::{cmp::{max, min}, io::{self, IoSliceMut}, iter};
 use tokio_io::{AsyncRead, AsyncWrite};
 
 /// A rate-limited resource.
-#[derive(Clone, Debug)]
+///
+/// Every read and write is clamped to the capacity granted by the `Limiter`,
+/// so the number of bytes accepted per time window never exceeds the rate,
+/// regardless of any buffering done by the wrapped resource. Flushing and
+/// shutting down do not accept new bytes and are passed through as is.
+///
+/// Resources created with `Limited::new_buffered` accept writes into an
+/// internal buffer instead, which is written to the wrapped resource at the
+/// rate permitted by the `Limiter` on subsequent writes and on `flush`.
+///
+/// When the capacity of the current time window is exhausted, reads and
+/// writes fail with `WouldBlock` (unless configured otherwise with
+/// `LimiterBuilder::throttle_error_kind`), which `poll_read` and `poll_write` report
+/// as `NotReady` after the current task has been enqueued to be notified on
+/// the next tick. This makes `Limited` resources compose with codecs such as
+/// `Framed`: partial frames stay in the codec's buffers and decoding or
+/// encoding resumes once capacity is available again. The error carries a
+/// `ThrottleReason`, which tells why the resource has been throttled.
+///
+/// Every `Limited` resource is a separate part of its `Limiter`, which is
+/// why it does not implement `Clone`. Use `Limited::try_clone` instead,
+/// which registers the copy as a new part.
+///
+/// Trait objects can be rate-limited as well, e.g. a connection boxed as
+/// `Box<dyn AsyncRead + Send>` to erase its type is wrapped in a
+/// `Limited<Box<dyn AsyncRead + Send>>`, which is `Send` in turn.
+///
+/// Resources created with `Limited::new_chained` are subject to several
+/// limiters at once and transfer at most what the most restrictive one
+/// permits. Settings such as the weight apply to the first limiter only.
+#[derive(Debug)]
 pub struct Limited<T> {
     id: Id,
     io: T,
     lim: Limiter,
+    chain: Vec<(Limiter, Id)>, // further limiters and the parts of this resource
+    pending: Vec<u8>, // buffered bytes not yet written to `io`
+    buffer: usize, // capacity of the write buffer
+    inflight: Vec<Token>, // capacity acquired for the I/O operation in progress
+    turns: Turns, // arbitration between reads and writes
+    strict: bool, // accept writes only as a whole
+    carried: usize, // capacity accumulated for coalesced reads and not read yet
+    read: u64, // number of bytes read from `io`
+    written: u64, // number of bytes written to `io`
 }
 
 impl<T> Limited<T> {
+    /// Create a rate-limited resource as a new part of the given `Limiter`.
+    ///
+    /// Fails with `Error::TooManyParts` if the `Limiter` already has the
+    /// maximum number of parts configured with `LimiterBuilder::max_parts`.
     pub fn new(io: T, lim: Limiter) -> Result<Limited<T>> {
         let id = lim.register()?;
-        Ok(Limited { id, io, lim })
+        Ok(Limited::from_id(io, lim, id))
+    }
+
+    /// Create a rate-limited resource from a part which has already been
+    /// registered with the given `Limiter`, e.g. with
+    /// `Limiter::register_many`. The part is deregistered when the resource
+    /// is dropped.
+    pub fn from_id(io: T, lim: Limiter, id: Id) -> Limited<T> {
+        Limited {
+            id,
+            io,
+            lim,
+            chain: Vec::new(),
+            pending: Vec::new(),
+            buffer: 0,
+            inflight: Vec::new(),
+            turns: Turns::default(),
+            strict: false,
+            carried: 0,
+            read: 0,
+            written: 0
+        }
+    }
+
+    /// Create a rate-limited resource which is subject to all of the given
+    /// limiters, e.g. a global one and one per category of resources.
+    ///
+    /// # Panics
+    ///
+    /// If `lims` is empty.
+    pub fn new_chained(io: T, lims: &[Limiter]) -> Result<Limited<T>> {
+        let (first, rest) = lims.split_first().expect("at least one limiter is given");
+        let mut this = Limited::new(io, first.clone())?;
+        for lim in rest {
+            // on error, dropping `this` deregisters all parts again
+            let id = lim.register()?;
+            this.chain.push((lim.clone(), id))
+        }
+        Ok(this)
+    }
+
+    /// Create a rate-limited resource tagged with an external ID, e.g. of
+    /// the application's connection. The tag is included in log output and
+    /// in `Limited::stats`.
+    pub fn new_tagged(io: T, lim: Limiter, tag: u64) -> Result<Limited<T>> {
+        let this = Limited::new(io, lim)?;
+        this.lim.set_tag(this.id, tag);
+        Ok(this)
+    }
+
+    /// Create a rate-limited resource with the given priority level. The
+    /// default level of other resources is 0.
+    ///
+    /// Resources of a higher level take precedence over all resources of a
+    /// lower level, regardless of their weights: the latter only get the
+    /// capacity left over, i.e. what the former are not expected to take
+    /// during a time window based on what they took during the previous
+    /// one. A resource of a higher level which takes all capacity starves
+    /// all resources of lower levels.
+    pub fn new_prioritized(io: T, lim: Limiter, level: u8) -> Result<Limited<T>> {
+        let this = Limited::new(io, lim)?;
+        this.lim.set_priority(this.id, level);
+        Ok(this)
+    }
+
+    /// Create a rate-limited resource which buffers up to `capacity` bytes
+    /// of writes. Buffered bytes are written to `io` at the rate permitted
+    /// by the `Limiter`, whenever this resource is written to or flushed.
+    pub fn new_buffered(io: T, lim: Limiter, capacity: usize) -> Result<Limited<T>> {
+        let mut this = Limited::new(io, lim)?;
+        this.pending.reserve_exact(capacity);
+        this.buffer = capacity;
+        Ok(this)
+    }
+
+    /// Create a rate-limited resource which, in addition to the limits
+    /// of the shared `Limiter`, never exceeds `per_conn_max` bytes per
+    /// second, even if all other parts of the `Limiter` are idle.
+    pub fn new_capped(io: T, lim: Limiter, per_conn_max: usize) -> Result<Limited<T>> {
+        let this = Limited::new(io, lim)?;
+        this.lim.set_cap(this.id, per_conn_max);
+        Ok(this)
+    }
+
+    /// Create a rate-limited resource which is guaranteed at least
+    /// `min_rate` bytes per second, e.g. for latency-sensitive control
+    /// connections. The guaranteed rate is set aside before the rest of the
+    /// capacity is divided among all parts, so other parts get less.
+    ///
+    /// Fails with `Error::Overcommitted` if the guaranteed rates of all
+    /// parts would exceed the rate of the `Limiter`.
+    pub fn new_guaranteed(io: T, lim: Limiter, min_rate: usize) -> Result<Limited<T>> {
+        let this = Limited::new(io, lim)?;
+        this.lim.set_floor(this.id, min_rate)?;
+        Ok(this)
+    }
+
+    /// Create a resource which is not rate-limited, so that `Limited<T>` can
+    /// be used uniformly whether rate limiting is enabled or not, instead of
+    /// boxing either `Limited<T>` or `T`.
+    ///
+    /// The resource is the only part of a limiter of its own, which has no
+    /// background timer and whose capacity of `usize::MAX` bytes is never
+    /// exhausted in practice, so reads and writes are never throttled.
+    pub fn passthrough(io: T) -> Limited<T> {
+        Limited::new(io, Limiter::unlimited()).expect("a new limiter has no parts")
+    }
+
+    /// The state of this resource's part of the `Limiter`.
+    pub fn stats(&self) -> PartStats {
+        self.lim.part_stats(self.id).expect("part is registered as long as `self` exists")
+    }
+
+    /// The number of bytes read from the wrapped resource since this
+    /// resource has been created.
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+
+    /// The number of bytes written to the wrapped resource since this
+    /// resource has been created. Bytes in the buffer of a resource created
+    /// with `Limited::new_buffered` are counted once they are written.
+    pub fn bytes_written(&self) -> u64 {
+        self.written
+    }
+
+    /// The ID of this resource's part.
+    pub(crate) fn id(&self) -> Id {
+        self.id
+    }
+
+    /// Change the weight of this resource relative to the other parts of
+    /// the `Limiter`, which all start out with weight 1. The share of a part
+    /// is proportional to its weight. With weight 0, no capacity is granted
+    /// at all, i.e. reads and writes are blocked until the weight is raised.
+    pub fn set_weight(&mut self, weight: u32) {
+        self.lim.set_weight(self.id, weight)
+    }
+
+    /// Let this resource go over the limit by up to `max` bytes once the
+    /// capacity of the current time window is exhausted, e.g. for control
+    /// frames which must be sent right away. The overdraft is repaid from
+    /// the capacity of the following time windows, so in the long run the
+    /// rate is still respected. By default, no overdraft is allowed.
+    ///
+    /// The overdraft applies to the first limiter only.
+    pub fn set_overdraft(&mut self, max: usize) {
+        self.lim.set_overdraft(self.id, max)
+    }
+
+    /// In strict mode, a write is only accepted as a whole, i.e. it is
+    /// throttled instead of writing a part of the given bytes, e.g.
+    /// for protocols which can not deal with short writes well. Writes of
+    /// more bytes than the `Limiter` makes available per time window are
+    /// still cut short to that amount, as they could never be accepted.
+    ///
+    /// A write larger than this resource's share of a time window is only
+    /// accepted once other parts leave enough capacity. Writes into the
+    /// buffer of a resource created with `Limited::new_buffered` are not
+    /// affected.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict
+    }
+
+    /// Temporarily exclude this resource from the division of capacity
+    /// among the parts of the `Limiter`, e.g. while it is not being used.
+    /// Until `resume` is called, no capacity is granted to it.
+    pub fn pause(&self) {
+        self.lim.set_paused(self.id, true)
+    }
+
+    /// Make a paused resource participate in the division of capacity again.
+    pub fn resume(&self) {
+        self.lim.set_paused(self.id, false)
+    }
+
+    /// The `Limiter` this resource is a part of. For resources created with
+    /// `Limited::new_chained`, this is the first limiter.
+    pub fn limiter(&self) -> Limiter {
+        self.lim.clone()
+    }
+
+    /// Stop granting capacity to this resource, e.g. to shut down a
+    /// connection gracefully. I/O operations already in progress complete
+    /// with the capacity they have been granted, after which reads return
+    /// end-of-file and writes return `Ok(0)`.
+    pub fn close_gracefully(&self) {
+        self.lim.close(self.id);
+        for (lim, id) in &self.chain {
+            lim.close(*id)
+        }
+    }
+
+    /// Clone this resource and register the clone as a new part of the
+    /// same `Limiter`.
+    pub fn try_clone(&self) -> Result<Limited<T>>
+    where
+        T: Clone
+    {
+        let lims = iter::once(&self.lim).chain(self.chain.iter().map(|(lim, _)| lim)).cloned().collect::<Vec<_>>();
+        Limited::new_chained(self.io.clone(), &lims)
     }
 }
 
 impl<T> Drop for Limited<T> {
     fn drop(&mut self) {
-        self.lim.deregister(self.id)
+        let lims = iter::once(&self.lim).chain(self.chain.iter().map(|(lim, _)| lim));
+        for (lim, t) in lims.zip(self.inflight.drain(..)) {
+            lim.release(t)
+        }
+        // capacity set aside for either direction is given back while the
+        // parts still exist
+        self.turns = Turns::default();
+        self.lim.deregister(self.id);
+        for (lim, id) in &self.chain {
+            lim.deregister(*id)
+        }
+    }
+}
+
+/// Borrow the `Quota` of a `Limited` resource, independently of its other fields.
+macro_rules! quota {
+    ($this: expr, $dir: expr) => {
+        Quota::new(&$this.lim, $this.id, &$this.chain, &mut $this.inflight, &mut $this.turns, $dir)
     }
 }
 
 impl<T: AsyncRead> io::Read for Limited<T> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.lim.get(self.id, buf.len()) {
-            Ok(mut t) => {
-                let n = t.get();
-                let k = min(buf.len(), n);
-                match self.io.read(&mut buf[0..k]) {
-                    Err(e) => Err(e),
-                    Ok(m) => {
-                        t.set(n - m);
-                        self.lim.release(t);
-                        Ok(m)
-                    }
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        let n = if self.lim.coalesce() > 0 && self.chain.is_empty() {
+            self.read_coalesced(buf)?
+        } else {
+            let io = &mut self.io;
+            quota!(self, Direction::Read).transfer(buf.len(), |k| io.read(&mut buf[0..k]))?
+        };
+        self.read += n as u64;
+        Ok(n)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+        if len == 0 {
+            return Ok(0)
+        }
+        let io = &mut self.io;
+        let n = quota!(self, Direction::Read).transfer(len, |mut k| {
+            // only pass on as many buffers as the granted quantity covers
+            let mut limited = Vec::with_capacity(bufs.len());
+            for b in bufs.iter_mut() {
+                if k == 0 {
+                    break
                 }
+                let n = min(b.len(), k);
+                limited.push(IoSliceMut::new(&mut b[.. n]));
+                k -= n
             }
-            Err(Error::NoCapacity) => {
-                self.lim.enqueue(self.id).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-                Err(io::Error::new(io::ErrorKind::WouldBlock, "rate limited"))
+            io.read_vectored(&mut limited)
+        })?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for Limited<T> {}
+
+impl<T: AsyncRead> Limited<T> {
+    /// Accumulate capacity across time windows until at least the minimum
+    /// size of coalesced reads, or the whole buffer, can be read at once.
+    /// Accumulated capacity is taken from the limiter right away and what
+    /// is not read is kept for the next read.
+    fn read_coalesced(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let min_read = min(self.lim.coalesce(), buf.len());
+        while self.carried < min_read {
+            match self.lim.acquire(self.id, buf.len() - self.carried) {
+                Ok(t) => self.carried += t.consume(),
+                Err(Error::NoCapacity) => {
+                    self.lim.enqueue(self.id)?;
+                    return Err(self.lim.throttled(self.id))
+                }
+                Err(Error::Closed) => return Ok(0),
+                Err(e) => return Err(e.into())
             }
-            Err(Error::Io(e)) => Err(e),
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
         }
+        let k = min(self.carried, buf.len());
+        let n = min(self.io.read(&mut buf[.. k])?, k);
+        self.carried -= n;
+        Ok(n)
     }
 }
 
-impl<T: AsyncRead> AsyncRead for Limited<T> {}
+impl<T> Limited<T> {
+    /// Is the given error due to throttling or the wrapped resource not
+    /// being ready?
+    fn would_block(&self, e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::WouldBlock || e.kind() == self.lim.throttle_error_kind()
+    }
+}
 
-impl<T: io::Write> io::Write for Limited<T> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.lim.get(self.id, buf.len()) {
-            Ok(mut t) => {
-                let n = t.get();
-                let k = min(buf.len(), n);
-                match self.io.write(&buf[0..k]) {
-                    Err(e) => Err(e),
-                    Ok(m) => {
-                        t.set(n - m);
-                        self.lim.release(t);
-                        Ok(m)
-                    }
+impl<T: io::Write> Limited<T> {
+    /// Write buffered bytes to the wrapped resource, as far as the rate permits.
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            let (io, pending) = (&mut self.io, &self.pending);
+            match quota!(self, Direction::Write).transfer(pending.len(), |k| io.write(&pending[0..k]))? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => {
+                    self.pending.drain(.. n);
+                    self.written += n as u64
                 }
             }
-            Err(Error::NoCapacity) => {
-                self.lim.enqueue(self.id).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-                Err(io::Error::new(io::ErrorKind::WouldBlock, "rate limited"))
+        }
+        Ok(())
+    }
+}
+
+impl<T: io::Write> io::Write for Limited<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        if self.buffer == 0 {
+            let mut quota = quota!(self, Direction::Write);
+            if self.strict {
+                quota = quota.require(buf.len())
             }
-            Err(Error::Io(e)) => Err(e),
-            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+            let io = &mut self.io;
+            let n = quota.transfer(buf.len(), |k| io.write(&buf[0..k]))?;
+            self.written += n as u64;
+            return Ok(n)
+        }
+        match self.drain() {
+            Ok(()) => {}
+            Err(ref e) if self.would_block(e) && self.pending.len() < self.buffer => {}
+            Err(e) => return Err(e)
         }
+        let k = min(buf.len(), self.buffer - self.pending.len());
+        self.pending.extend_from_slice(&buf[.. k]);
+        Ok(k)
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
         self.io.flush()
     }
 }
 
+/// The limiters of a `Limited` resource, borrowed independently of the
+/// wrapped resource.
+struct Quota<'a> {
+    parts: Vec<(&'a Limiter, Id)>, // the limiters and the parts of the resource
+    inflight: &'a mut Vec<Token>, // capacity acquired for the I/O operation in progress
+    turns: &'a mut Turns,
+    dir: Direction, // the direction of the I/O operation
+    required: usize, // the minimum quantity to transfer
+}
+
+/// The direction of an I/O operation.
+#[derive(Clone, Copy, Debug)]
+enum Direction {
+    Read = 0,
+    Write = 1,
+}
+
+/// Reads and writes of a resource compete for the same share of capacity.
+///
+/// Once a direction has been turned away, the other one uses at most half
+/// of the capacity granted to it next and sets the rest aside for the
+/// direction turned away, whose task is notified right away. Capacity set
+/// aside is not available to other parts, but it expires at the end of the
+/// time window. A direction is set capacity aside for at most once per
+/// time it has been turned away, so a direction which stops asking, e.g.
+/// at the end of the stream, does not hold the other one back.
+#[derive(Debug, Default)]
+struct Turns {
+    turned_away: [Option<usize>; 2], // time window in which a direction last got no capacity
+    aside: [Vec<Token>; 2], // capacity set aside for a direction, one token per limiter
+}
+
+impl Turns {
+    /// Has the given direction been turned away during the current or the
+    /// previous time window and not been served since?
+    fn is_waiting(&self, dir: Direction, index: usize) -> bool {
+        self.turned_away[dir as usize].is_some_and(|i| i + 1 >= index)
+    }
+
+    /// Has capacity of the given time window been set aside for the given
+    /// direction?
+    fn has_aside(&self, dir: Direction, index: usize) -> bool {
+        self.aside[dir as usize].first().is_some_and(|t| t.index() == index)
+    }
+}
+
+impl Direction {
+    fn other(self) -> Direction {
+        match self {
+            Direction::Read => Direction::Write,
+            Direction::Write => Direction::Read
+        }
+    }
+}
+
+impl<'a> Quota<'a> {
+    fn new(
+        lim: &'a Limiter,
+        id: Id,
+        chain: &'a [(Limiter, Id)],
+        inflight: &'a mut Vec<Token>,
+        turns: &'a mut Turns,
+        dir: Direction
+    ) -> Self {
+        let parts = iter::once((lim, id)).chain(chain.iter().map(|(lim, id)| (lim, *id))).collect();
+        Quota { parts, inflight, turns, dir, required: 0 }
+    }
+
+    /// Transfer either at least `len` bytes or nothing at all. If `len`
+    /// exceeds the rate of a limiter, that rate is required instead.
+    fn require(mut self, len: usize) -> Self {
+        self.required = self.parts.iter().map(|(lim, _)| lim.rate()).fold(len, min);
+        self
+    }
+
+    /// Acquire capacity for up to `len` bytes from every limiter and let `f`
+    /// transfer at most the smallest quantity granted. Capacity which `f` did
+    /// not use, e.g. because of a short read or write or an error, is given
+    /// back. Nothing is transferred once a part has been closed.
+    ///
+    /// A lack of capacity always results in an error of the limiter's
+    /// throttle error kind, `WouldBlock` by default, never in `Ok(0)`, which
+    /// callers like `copy` take as the end of the stream. `Ok(0)` means that
+    /// `f` transferred nothing or that the part has been closed.
+    ///
+    /// If `f` reports more bytes than it has been granted, which only a
+    /// misbehaving resource does, the result is clamped to the quantity
+    /// granted, so that the accounting stays consistent.
+    ///
+    /// While `f` runs, the tokens are kept in `inflight`, so that they can
+    /// still be given back if `f` panics.
+    fn transfer<F>(mut self, len: usize, f: F) -> io::Result<usize>
+    where
+        F: FnOnce(usize) -> io::Result<usize>
+    {
+        let (lim, id) = self.parts[0];
+        let index = lim.tick();
+        let (this, other) = (self.dir, self.dir.other());
+        // capacity set aside by the other direction is used first, unless
+        // it has expired
+        let aside = self.turns.has_aside(this, index);
+        let mut tokens = std::mem::take(&mut self.turns.aside[this as usize]).into_iter();
+        let mut quant = len;
+        for &(lim, id) in &self.parts {
+            let granted = match tokens.next() {
+                Some(t) if aside && t.index() == lim.tick() => Ok(t),
+                _ => lim.acquire(id, quant)
+            };
+            match granted {
+                Ok(mut t) => {
+                    // the first token is at most the part's share
+                    while t.get() < self.required {
+                        match lim.acquire(id, self.required - t.get()) {
+                            Ok(more) => t.merge(more),
+                            Err(_) => break
+                        }
+                    }
+                    quant = min(quant, t.get());
+                    self.inflight.push(t)
+                }
+                Err(e) => {
+                    self.release(0);
+                    return match e {
+                        Error::NoCapacity => {
+                            self.turns.turned_away[this as usize] = Some(index);
+                            lim.enqueue_in(id, this as usize)?;
+                            if self.turns.has_aside(other, index) {
+                                Err(lim.throttled_by(ThrottleReason::Turn))
+                            } else {
+                                Err(lim.throttled(id))
+                            }
+                        }
+                        Error::Closed => Ok(0),
+                        e => Err(e.into())
+                    }
+                }
+            }
+        }
+        if quant < self.required {
+            self.release(0);
+            self.turns.turned_away[this as usize] = Some(index);
+            lim.enqueue_in(id, this as usize)?;
+            return Err(lim.throttled(id))
+        }
+        // capacity set aside is not divided any further
+        let sharing = !aside && self.turns.is_waiting(other, index);
+        if sharing {
+            quant = max(quant.div_ceil(2), self.required)
+        }
+        self.turns.turned_away[this as usize] = None;
+        debug_assert!(quant > 0, "tokens are never empty");
+        let result = f(quant).map(|n| min(n, quant));
+        let used = *result.as_ref().unwrap_or(&0);
+        let rest = self.unused(used);
+        if sharing && rest.iter().all(|t| t.get() > 0) {
+            // the other direction has been served by setting the rest aside
+            self.turns.turned_away[other as usize] = None;
+            self.turns.aside[other as usize] = rest;
+            lim.wake(id, other as usize)
+        } else {
+            self.give_back(rest)
+        }
+        result
+    }
+
+    /// Give back all tokens in flight, except for the `used` quantity.
+    fn release(&mut self, used: usize) {
+        let rest = self.unused(used);
+        self.give_back(rest)
+    }
+
+    /// Take all tokens in flight, reduced to the quantity not `used`.
+    fn unused(&mut self, used: usize) -> Vec<Token> {
+        self.inflight.drain(..).map(|mut t| {
+            let n = t.get();
+            t.set(n - used);
+            t
+        })
+        .collect()
+    }
+
+    /// Give the given tokens back to their limiters.
+    fn give_back(&self, tokens: Vec<Token>) {
+        for (&(lim, _), t) in self.parts.iter().zip(tokens) {
+            lim.release(t)
+        }
+    }
+}
+
+impl<T: AsyncWrite> Limited<T> {
+    /// Check if this resource currently has capacity to write, without
+    /// acquiring any. If not, the current task is notified once new
+    /// capacity is available.
+    pub fn poll_write_ready(&mut self) -> Poll<(), io::Error> {
+        let parts = iter::once((&self.lim, self.id)).chain(self.chain.iter().map(|(lim, id)| (lim, *id)));
+        for (lim, id) in parts {
+            match lim.peek(id) {
+                Ok(0) => {
+                    lim.enqueue_in(id, Direction::Write as usize)?;
+                    return Ok(Async::NotReady)
+                }
+                Ok(_) => {}
+                // writing observes the closed part
+                Err(Error::Closed) => return Ok(Async::Ready(())),
+                Err(e) => return Err(e.into())
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
 impl<T: AsyncWrite> AsyncWrite for Limited<T> {
+    /// Write all buffered bytes, as fast as the rate permits, before
+    /// shutting down the wrapped resource.
     fn shutdown(&mut self) -> Poll<(), io::Error> {
-        self.io.shutdown()
+        match self.drain() {
+            Ok(()) => self.io.shutdown(),
+            Err(ref e) if self.would_block(e) => Ok(Async::NotReady),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+/// Write the whole buffer to the given `Limited` resource, paced by its
+/// limiter, and resolve to the resource and the buffer.
+///
+/// Unlike looping on partial writes, the returned future yields whenever
+/// the resource is throttled and is polled again on the next tick, so it
+/// does not retry busily. Buffers larger than a time window's share are
+/// written over several time windows.
+pub fn limited_write_all<T, B>(limited: Limited<T>, buf: B) -> LimitedWriteAll<T, B>
+where
+    T: AsyncWrite,
+    B: AsRef<[u8]>
+{
+    LimitedWriteAll { state: Some((limited, buf)), pos: 0 }
+}
+
+/// A `Future` which writes a whole buffer to a `Limited` resource, see
+/// `limited_write_all`.
+#[derive(Debug)]
+pub struct LimitedWriteAll<T, B> {
+    state: Option<(Limited<T>, B)>,
+    pos: usize, // the number of bytes written so far
+}
+
+impl<T, B> Future for LimitedWriteAll<T, B>
+where
+    T: AsyncWrite,
+    B: AsRef<[u8]>
+{
+    type Item = (Limited<T>, B);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        {
+            let (limited, buf) = self.state.as_mut().expect("LimitedWriteAll polled after completion");
+            let buf = buf.as_ref();
+            while self.pos < buf.len() {
+                match io::Write::write(limited, &buf[self.pos ..]) {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => self.pos += n,
+                    // the task is notified once capacity is available again
+                    Err(ref e) if limited.would_block(e) => return Ok(Async::NotReady),
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+        Ok(Async::Ready(self.state.take().expect("checked above")))
+    }
+}
+
+/// A `Future` which copies all bytes of a reader to a `Limited` writer and
+/// resolves to the number of bytes copied, see `Limiter::transfer`.
+#[derive(Debug)]
+pub(crate) struct Transfer<R, W> {
+    reader: R,
+    writer: Limited<W>,
+    buf: Box<[u8]>,
+    pos: usize, // the start of the bytes in `buf` not yet written
+    end: usize, // the end of the bytes in `buf` read so far
+    eof: bool,
+    amount: u64,
+}
+
+impl<R: AsyncRead, W: AsyncWrite> Transfer<R, W> {
+    pub(crate) fn new(reader: R, writer: Limited<W>) -> Transfer<R, W> {
+        Transfer {
+            reader,
+            writer,
+            buf: Box::new([0; 2048]),
+            pos: 0,
+            end: 0,
+            eof: false,
+            amount: 0
+        }
+    }
+}
+
+impl<R: AsyncRead, W: AsyncWrite> Future for Transfer<R, W> {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<u64, Error> {
+        loop {
+            if self.pos == self.end && !self.eof {
+                match self.reader.poll_read(&mut self.buf)? {
+                    Async::Ready(0) => self.eof = true,
+                    Async::Ready(n) => {
+                        self.pos = 0;
+                        self.end = n
+                    }
+                    Async::NotReady => return Ok(Async::NotReady)
+                }
+            }
+            while self.pos < self.end {
+                match io::Write::write(&mut self.writer, &self.buf[self.pos .. self.end]) {
+                    Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+                    Ok(n) => {
+                        self.pos += n;
+                        self.amount += n as u64
+                    }
+                    // the task is notified once capacity is available again
+                    Err(ref e) if self.writer.would_block(e) => return Ok(Async::NotReady),
+                    Err(e) => return Err(e.into())
+                }
+            }
+            if self.eof {
+                match io::Write::flush(&mut self.writer) {
+                    Ok(()) => return Ok(Async::Ready(self.amount)),
+                    Err(ref e) if self.writer.would_block(e) => return Ok(Async::NotReady),
+                    Err(e) => return Err(e.into())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use parking_lot::Mutex;
+    use futures::executor;
+    use std::{
+        collections::VecDeque,
+        io::{BufWriter, Read, Write},
+        sync::{atomic::{AtomicUsize, Ordering}, Arc}
+    };
+    use super::*;
+    use crate::testing::Wakeups;
+    use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+
+    #[test]
+    fn buffered_writes_respect_rate() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut w = Limited::new(BufWriter::with_capacity(1000, Vec::new()), lim.clone()).unwrap();
+            let buf = [0; 1000];
+            for i in 1 ..= 5 {
+                let mut accepted = 0;
+                loop {
+                    match w.write(&buf) {
+                        Ok(n) => accepted += n,
+                        Err(e) => {
+                            assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+                            break
+                        }
+                    }
+                }
+                w.flush().unwrap();
+                assert_eq!(100, accepted);
+                assert_eq!(100 * i, w.io.get_ref().len());
+                clock.tick();
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn zero_length_io_is_not_throttled() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut l = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            assert_eq!(0, l.read(&mut []).unwrap());
+            let mut l = Limited::new(io::sink(), lim.clone()).unwrap();
+            assert_eq!(0, l.write(&[]).unwrap());
+            assert_eq!(0, clock.tick().waiting);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn capped_part_does_not_exceed_cap() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut l = Limited::new_capped(io::repeat(1), lim, 10).unwrap();
+            let mut buf = [0; 100];
+            for _ in 0 .. 5 {
+                assert_eq!(10, l.read(&mut buf).unwrap());
+                let e = l.read(&mut buf).unwrap_err();
+                assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+                assert_eq!(10, clock.tick().served)
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn share_grows_with_weight() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut a = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            let _b = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            let mut buf = [0; 1000];
+            assert_eq!(50, a.read(&mut buf).unwrap());
+            a.set_weight(3);
+            clock.tick();
+            assert_eq!(75, a.read(&mut buf).unwrap());
+            a.set_weight(0);
+            clock.tick();
+            assert_eq!(io::ErrorKind::WouldBlock, a.read(&mut buf).unwrap_err().kind());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn higher_priority_takes_precedence() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut high = Limited::new_prioritized(io::sink(), lim.clone(), 1).unwrap();
+            let mut low = Limited::new(io::sink(), lim.clone()).unwrap();
+            let mut window = |high_demand: usize| {
+                // the low priority resource always asks first
+                let low_got = low.write(&[0; 1000]).unwrap_or(0);
+                let high_got = high.write(&vec![0; high_demand]).unwrap_or(0);
+                clock.tick();
+                (low_got, high_got)
+            };
+            // without history, the high priority resource is not expected
+            assert_eq!((100, 0), window(30));
+            // the resource which did not get what it asked for takes it all
+            assert_eq!((0, 30), window(30));
+            for _ in 0 .. 3 {
+                assert_eq!((70, 30), window(30))
+            }
+            assert_eq!((70, 30), window(1000));
+            // a saturating high priority resource starves the other one
+            for _ in 0 .. 3 {
+                assert_eq!((0, 100), window(1000))
+            }
+            assert_eq!((0, 30), window(30));
+            assert_eq!((70, 30), window(30));
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn overdraft_is_repaid() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut control = Limited::new(io::sink(), lim.clone()).unwrap();
+            let mut bulk = Limited::new(io::sink(), lim.clone()).unwrap();
+            control.set_overdraft(30);
+            while bulk.write(&[0; 1000]).is_ok() {}
+            // the control resource borrows ahead
+            assert_eq!(20, control.write(&[0; 20]).unwrap());
+            assert_eq!(10, control.write(&[0; 20]).unwrap());
+            assert!(control.write(&[0; 20]).is_err());
+            assert_eq!(130, lim.total_transferred());
+            clock.tick();
+            // the debt is repaid first
+            assert_eq!(70, lim.available());
+            for _ in 0 .. 10 {
+                while bulk.write(&[0; 1000]).is_ok() {}
+                while control.write(&[0; 20]).is_ok() {}
+                clock.tick();
+            }
+            // at most the overdraft is transferred on top of the rate
+            assert_eq!(11 * 100 + 30, lim.total_transferred());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn paused_parts_are_not_considered() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(120);
+            let mut a = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            let mut b = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            let mut c = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            let mut buf = [0; 1000];
+            c.pause();
+            assert_eq!(60, a.read(&mut buf).unwrap());
+            assert_eq!(60, b.read(&mut buf).unwrap());
+            assert_eq!(io::ErrorKind::WouldBlock, c.read(&mut buf).unwrap_err().kind());
+            c.resume();
+            clock.tick();
+            assert_eq!(40, a.read(&mut buf).unwrap());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn buffered_writes_are_drained_at_rate() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut w = Limited::new_buffered(Vec::new(), lim, 1000).unwrap();
+            assert_eq!(1000, w.write(&[1; 2000]).unwrap());
+            assert_eq!(0, w.io.len());
+            for i in 1 ..= 10 {
+                let result = w.flush();
+                assert_eq!(100 * i, w.io.len());
+                if i < 10 {
+                    assert_eq!(io::ErrorKind::WouldBlock, result.unwrap_err().kind());
+                    clock.tick();
+                } else {
+                    assert!(result.is_ok())
+                }
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    /// A resource which reports more bytes written than it has been given.
+    struct OverReporting(usize);
+
+    impl Write for OverReporting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len() + 10)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn over_reported_writes_are_clamped() {
+        future::lazy(|| {
+            let (lim, _clock) = Limiter::with_manual_clock(100);
+            let mut w = Limited::new(OverReporting(0), lim.clone()).unwrap();
+            assert_eq!(30, w.write(&[0; 30]).unwrap());
+            assert_eq!(30, lim.total_transferred());
+            assert_eq!(70, lim.available());
+            assert_eq!(70, w.write(&[0; 100]).unwrap());
+            assert!(w.write(&[0; 100]).is_err());
+            assert_eq!(100, w.io.0);
+            assert_eq!(100, lim.total_transferred());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn vectored_reads_respect_token_size() {
+        future::lazy(|| {
+            let (lim, _clock) = Limiter::with_manual_clock(100);
+            let mut r = Limited::new(io::repeat(1), lim).unwrap();
+            let mut a = [0; 80];
+            let mut b = [0; 80];
+            let n = r.read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)]).unwrap();
+            assert_eq!(100, n);
+            assert!(a.iter().all(|&x| x == 1));
+            assert_eq!(20, b.iter().filter(|&&x| x == 1).count());
+            let e = r.read_vectored(&mut [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)]).unwrap_err();
+            assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    /// A writer which accepts at most 7 bytes per call and fails every
+    /// third call.
+    struct Short(Vec<u8>, usize);
+
+    impl Write for Short {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1 += 1;
+            if self.1 % 3 == 0 {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+            let n = min(7, buf.len());
+            self.0.extend_from_slice(&buf[.. n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_writes_release_unused_capacity() {
+        future::lazy(|| {
+            let (lim, _clock) = Limiter::with_manual_clock(100);
+            let mut w = Limited::new(Short(Vec::new(), 0), lim.clone()).unwrap();
+            for _ in 0 .. 9 {
+                let _ = w.write(&[0; 50]);
+                assert_eq!(100 - w.io.0.len(), lim.available());
+                assert_eq!(w.io.0.len() as u64, lim.total_transferred())
+            }
+            assert_eq!(42, w.io.0.len());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    /// A reader which is never ready.
+    struct NotReady;
+
+    impl Read for NotReady {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for NotReady {}
+
+    #[test]
+    fn blocked_reads_release_capacity() {
+        future::lazy(|| {
+            let (lim, _clock) = Limiter::with_manual_clock(100);
+            let mut r = Limited::new(NotReady, lim.clone()).unwrap();
+            let other = lim.register().unwrap();
+            for _ in 0 .. 3 {
+                let e = r.read(&mut [0; 60]).unwrap_err();
+                assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+                assert_eq!(100, lim.available());
+                assert_eq!(0, lim.total_transferred())
+            }
+            assert!(r.poll_read(&mut [0; 60]).unwrap().is_not_ready());
+            // the capacity is available to other parts
+            assert_eq!(100, lim.drain_chunks(other).map(Token::consume).sum::<usize>());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn throttle_reasons_are_reported() {
+        fn reason(e: io::Error) -> ThrottleReason {
+            assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+            *e.get_ref().and_then(|e| e.downcast_ref::<ThrottleReason>()).expect("reason is given")
+        }
+
+        future::lazy(|| {
+            let (lim, _clock) = Limiter::with_manual_clock(100);
+            let mut capped = Limited::new_capped(io::sink(), lim.clone(), 10).unwrap();
+            assert_eq!(10, capped.write(&[0; 50]).unwrap());
+            assert_eq!(ThrottleReason::Capped, reason(capped.write(&[0; 50]).unwrap_err()));
+            capped.pause();
+            assert_eq!(ThrottleReason::Paused, reason(capped.write(&[0; 50]).unwrap_err()));
+
+            let guaranteed = Limited::new_guaranteed(io::sink(), lim.clone(), 60).unwrap();
+            let mut w = Limited::new(io::sink(), lim.clone()).unwrap();
+            let mut throttled = || loop {
+                if let Err(e) = w.write(&[0; 50]) {
+                    break reason(e)
+                }
+            };
+            assert_eq!(ThrottleReason::Reserved, throttled());
+            assert_eq!(60, lim.available());
+            drop(guaranteed);
+            assert_eq!(ThrottleReason::NoCapacity, throttled());
+            assert_eq!(0, lim.available());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn too_many_parts() {
+        let (lim, _clock) = Limiter::builder(100).max_parts(4).build_with_manual_clock();
+        let parts = (0 .. 4).map(|_| Limited::new(io::sink(), lim.clone()).unwrap()).collect::<Vec<_>>();
+        match Limited::new(io::sink(), lim.clone()) {
+            Err(Error::TooManyParts) => {}
+            other => panic!("unexpected result: {:?}", other.map(|_| ()))
+        }
+        drop(parts);
+        assert!(Limited::new(io::sink(), lim).is_ok())
+    }
+
+    /// An in-memory pipe which counts the bytes written to it.
+    #[derive(Clone, Default)]
+    struct Pipe(Arc<Mutex<(VecDeque<u8>, usize)>>);
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut pipe = self.0.lock();
+            if pipe.0.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+            let n = min(buf.len(), pipe.0.len());
+            for (b, x) in buf.iter_mut().zip(pipe.0.drain(.. n)) {
+                *b = x
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut pipe = self.0.lock();
+            pipe.0.extend(buf);
+            pipe.1 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncRead for Pipe {}
+
+    impl AsyncWrite for Pipe {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn shutdown_writes_buffered_bytes() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let pipe = Pipe::default();
+            let mut w = Limited::new_buffered(pipe.clone(), lim, 1000).unwrap();
+            assert_eq!(1000, w.write(&[1; 1000]).unwrap());
+            for i in 1 ..= 10 {
+                let ready = w.shutdown().unwrap().is_ready();
+                assert_eq!(100 * i, pipe.0.lock().1);
+                assert_eq!(i == 10, ready);
+                clock.tick();
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn framed_messages_are_rate_limited() {
+        future::lazy(|| {
+            let (wlim, mut wclock) = Limiter::with_manual_clock(10);
+            let (rlim, mut rclock) = Limiter::with_manual_clock(10);
+            let pipe = Pipe::default();
+            let mut sink = FramedWrite::new(Limited::new(pipe.clone(), wlim).unwrap(), LengthDelimitedCodec::new());
+            let mut stream = FramedRead::new(Limited::new(pipe.clone(), rlim).unwrap(), LengthDelimitedCodec::new());
+            let frames = (0 .. 3u8).map(|i| vec![i; 12]).collect::<Vec<_>>();
+            for f in &frames {
+                assert!(sink.start_send(f.clone().into()).unwrap().is_ready())
+            }
+            let mut received = Vec::new();
+            let mut ticks = 0;
+            while received.len() < frames.len() {
+                let before = pipe.0.lock().1;
+                let _ = sink.poll_complete().unwrap();
+                // a frame of 4 + 12 bytes is written over several time windows
+                assert!(pipe.0.lock().1 - before <= 10);
+                while let Async::Ready(Some(f)) = stream.poll().unwrap() {
+                    received.push(f.to_vec())
+                }
+                wclock.tick();
+                rclock.tick();
+                ticks += 1
+            }
+            assert_eq!(frames, received);
+            assert!(ticks >= 48 / 10, "ticks = {}", ticks);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn parts_are_independent_of_rate() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(2);
+            let mut parts = (0 .. 10).map(|_| Limited::new(io::repeat(1), lim.clone()).unwrap()).collect::<Vec<_>>();
+            let mut read = vec![0; parts.len()];
+            for _ in 0 .. 5 {
+                for (p, n) in parts.iter_mut().zip(read.iter_mut()) {
+                    *n += p.read(&mut [0; 10]).unwrap_or(0)
+                }
+                clock.tick();
+            }
+            // everybody makes progress at the given rate
+            assert_eq!(vec![1; 10], read);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn write_readiness_reflects_capacity() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(10);
+            let mut w = Limited::new(io::sink(), lim.clone()).unwrap();
+            let _other = Limited::new(io::sink(), lim.clone()).unwrap();
+            for _ in 0 .. 3 {
+                assert!(w.poll_write_ready().unwrap().is_ready());
+                assert_eq!(10, lim.available());
+                assert_eq!(5, w.write(&[0; 100]).unwrap());
+                assert!(w.poll_write_ready().unwrap().is_ready());
+                assert_eq!(2, w.write(&[0; 100]).unwrap());
+                while w.write(&[0; 100]).is_ok() {}
+                assert!(w.poll_write_ready().unwrap().is_not_ready());
+                clock.tick();
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn write_all_is_paced_by_the_limiter() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let w = Limited::new(io::Cursor::new(Vec::new()), lim.clone()).unwrap();
+        let wakeups = Arc::new(Wakeups::default());
+        let handle = executor::NotifyHandle::from(wakeups.clone());
+        let mut write = executor::spawn(limited_write_all(w, vec![7; 350]));
+        let mut polls = 1;
+        let (w, buf) = loop {
+            if let Async::Ready(done) = write.poll_future_notify(&handle, 0).unwrap() {
+                break done
+            }
+            // not notified before the next tick
+            assert_eq!(polls - 1, wakeups.count());
+            clock.tick();
+            assert_eq!(polls, wakeups.count());
+            polls += 1
+        };
+        assert_eq!(4, polls);
+        assert_eq!(buf, *w.io.get_ref())
+    }
+
+    #[test]
+    fn boxed_resources_are_rate_limited() {
+        fn assert_send<T: Send>(_: &T) {}
+
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let r: Box<dyn AsyncRead + Send> = Box::new(io::repeat(1));
+            let w: Box<dyn AsyncWrite + Send> = Box::new(io::Cursor::new(Vec::new()));
+            let mut r = Limited::new(r, lim.clone()).unwrap();
+            let mut w = Limited::new(w, lim.clone()).unwrap();
+            assert_send(&r);
+            assert_send(&w);
+            let mut buf = [0; 200];
+            for _ in 0 .. 3 {
+                assert_eq!(50, r.read(&mut buf).unwrap());
+                assert_eq!(50, w.write(&buf).unwrap());
+                assert_eq!(io::ErrorKind::WouldBlock, r.read(&mut buf).unwrap_err().kind());
+                assert_eq!(io::ErrorKind::WouldBlock, w.write(&buf).unwrap_err().kind());
+                clock.tick();
+            }
+            assert!(w.poll_flush().unwrap().is_ready());
+            assert_eq!(300, lim.total_transferred());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn bytes_are_counted_per_direction() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let io = io::Cursor::new(vec![1; 1000]);
+            let mut c = Limited::new(io, lim.clone()).unwrap();
+            let mut buf = [0; 40];
+            assert_eq!(30, c.read(&mut buf[.. 30]).unwrap());
+            assert_eq!(30, c.write(&buf[.. 30]).unwrap());
+            assert_eq!(40, c.read(&mut buf).unwrap());
+            assert!(c.write(&buf).is_err());
+            clock.tick();
+            assert_eq!(25, c.write(&buf[.. 25]).unwrap());
+            assert_eq!(70, c.bytes_read());
+            assert_eq!(55, c.bytes_written());
+            // other resources of the same limiter are counted separately
+            let other = Limited::new(io::empty(), lim.clone()).unwrap();
+            assert_eq!(0, other.bytes_read());
+            assert_eq!(0, other.bytes_written());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn passthrough_is_not_throttled() {
+        // no task context is needed, as nothing is ever enqueued
+        let mut r = Limited::passthrough(io::repeat(1));
+        let mut w = Limited::passthrough(io::sink());
+        let mut buf = vec![0; 1 << 20];
+        for _ in 0 .. 100 {
+            assert_eq!(buf.len(), r.read(&mut buf).unwrap());
+            assert_eq!(buf.len(), w.write(&buf).unwrap())
+        }
+        let lim = w.limiter();
+        assert_eq!(1, lim.parts());
+        assert_eq!(0, lim.tick());
+        assert_eq!(0, lim.throttle_count())
+    }
+
+    #[test]
+    fn reads_are_coalesced() {
+        /// A resource which records the sizes of the reads of it.
+        struct Recorded(io::Cursor<Vec<u8>>, Vec<usize>);
+
+        impl Read for Recorded {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.1.push(buf.len());
+                self.0.read(buf)
+            }
+        }
+
+        impl AsyncRead for Recorded {}
+
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::builder(10).coalesce(50).build_with_manual_clock();
+            let mut r = Limited::new(Recorded(io::Cursor::new(vec![1; 120]), Vec::new()), lim).unwrap();
+            let mut buf = [0; 1000];
+            let mut reads = Vec::new();
+            let mut windows = 0;
+            loop {
+                match r.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => reads.push(n),
+                    Err(e) => {
+                        assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+                        clock.tick();
+                        windows += 1
+                    }
+                }
+            }
+            // the peer sending less than accumulated does not stall reads
+            assert_eq!(vec![50, 50, 20], reads);
+            assert!(r.io.1.iter().all(|&n| n >= 50), "reads = {:?}", r.io.1);
+            // 50 bytes take 5 time windows, the read at the end of the stream
+            // uses what is left over from the short read plus 20 bytes
+            assert_eq!(16, windows);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn copy_is_not_ended_by_saturation() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let hog = lim.register().unwrap();
+            let data = vec![1; 1000];
+            let r = Limited::new(&data[..], lim.clone()).unwrap();
+            let mut copy = tokio_io::io::copy(r, io::Cursor::new(Vec::new()));
+            let mut windows = 0;
+            let (n, _, w) = loop {
+                // every other time window, another part takes all capacity
+                if windows % 2 == 0 {
+                    lim.drain_chunks(hog).for_each(|t| { t.consume(); })
+                }
+                if let Async::Ready(result) = copy.poll().unwrap() {
+                    break result
+                }
+                clock.tick();
+                windows += 1
+            };
+            assert_eq!(1000, n);
+            assert_eq!(data, w.into_inner());
+            assert!(windows >= 10, "windows = {}", windows);
+            lim.deregister(hog);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn throttle_error_kind_is_configurable() {
+        future::lazy(|| {
+            let (lim, _clock) = Limiter::builder(10)
+                .throttle_error_kind(io::ErrorKind::Interrupted)
+                .build_with_manual_clock();
+            let mut r = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            assert_eq!(10, r.read(&mut [0; 100]).unwrap());
+            let e = r.read(&mut [0; 100]).unwrap_err();
+            assert_eq!(io::ErrorKind::Interrupted, e.kind());
+            assert_eq!(io::ErrorKind::Interrupted, lim.throttle_error_kind());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn strict_writes_are_all_or_nothing() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut w = Limited::new(io::sink(), lim.clone()).unwrap();
+            let _other = Limited::new(io::sink(), lim.clone()).unwrap();
+            w.set_strict(true);
+            // more than the share of the time window, but the other part is idle
+            assert_eq!(60, w.write(&[0; 60]).unwrap());
+            let e = w.write(&[0; 60]).unwrap_err();
+            assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+            assert_eq!(40, lim.available());
+            assert_eq!(30, w.write(&[0; 30]).unwrap());
+            clock.tick();
+            // writes larger than the rate are capped
+            assert_eq!(100, w.write(&[0; 200]).unwrap());
+            clock.tick();
+            w.set_strict(false);
+            assert_eq!(50, w.write(&[0; 60]).unwrap());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn tagged_parts() {
+        future::lazy(|| {
+            let (lim, _clock) = Limiter::with_manual_clock(100);
+            let mut r = Limited::new_tagged(io::repeat(1), lim.clone(), 7).unwrap();
+            let untagged = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            assert_eq!(50, r.read(&mut [0; 100]).unwrap());
+            let stats = r.stats();
+            assert_eq!((Some(7), 1, false, 50), (stats.tag, stats.weight, stats.paused, stats.used));
+            assert_eq!(Some(stats), lim.part_stats(r.id()));
+            assert_eq!(None, untagged.stats().tag);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    /// A reader which panics.
+    struct Panicking;
+
+    impl Read for Panicking {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            panic!("read failed")
+        }
+    }
+
+    impl AsyncRead for Panicking {}
+
+    #[test]
+    fn inflight_capacity_is_released_on_drop() {
+        let (lim, _clock) = Limiter::with_manual_clock(100);
+        let r = Limited::new(Panicking, lim.clone()).unwrap();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let mut r = r;
+            r.read(&mut [0; 10])
+        }));
+        assert!(result.is_err());
+        assert_eq!(0, lim.parts());
+        assert_eq!(100, lim.available());
+        assert_eq!(0, lim.total_transferred())
+    }
+
+    #[test]
+    fn limiter_of_a_resource() {
+        let (lim, _clock) = Limiter::with_manual_clock(100);
+        let a = Limited::new(io::empty(), lim.clone()).unwrap();
+        let b = Limited::new(io::empty(), a.limiter()).unwrap();
+        assert_eq!(2, lim.parts());
+        assert_eq!(lim.rate(), a.limiter().rate());
+        drop((a, b));
+        assert_eq!(0, lim.parts())
+    }
+
+    #[test]
+    fn clones_are_separate_parts() {
+        let (lim, _clock) = Limiter::with_manual_clock(100);
+        let a = Limited::new(io::empty(), lim.clone()).unwrap();
+        let b = a.try_clone().unwrap();
+        assert_ne!(a.id, b.id);
+        assert_eq!(2, lim.parts());
+        drop(a);
+        assert_eq!(1, lim.parts());
+        drop(b);
+        assert_eq!(0, lim.parts())
+    }
+
+    #[test]
+    fn chained_limiters_apply_the_smallest_budget() {
+        future::lazy(|| {
+            let (global, mut clock) = Limiter::with_manual_clock(100);
+            let mut conns = (0 .. 5)
+                .map(|_| {
+                    let local = global.clone_with_rate(30);
+                    Limited::new_chained(io::repeat(1), &[global.clone(), local]).unwrap()
+                })
+                .collect::<Vec<_>>();
+            for _ in 0 .. 3 {
+                let mut read = vec![0; conns.len()];
+                for (c, n) in conns.iter_mut().zip(read.iter_mut()) {
+                    while let Ok(k) = c.read(&mut [0; 64]) {
+                        *n += k
+                    }
+                }
+                assert!(read.iter().all(|&n| n <= 30));
+                assert_eq!(100, read.iter().sum::<usize>());
+                clock.tick();
+            }
+            // a single resource is limited by its own limiter
+            conns.truncate(1);
+            for _ in 0 .. 3 {
+                let mut n = 0;
+                while let Ok(k) = conns[0].read(&mut [0; 64]) {
+                    n += k
+                }
+                assert_eq!(30, n);
+                clock.tick();
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    /// A writer which closes its part while a write is in progress.
+    struct Closing {
+        lim: Limiter,
+        id: Option<Id>,
+    }
+
+    impl Write for Closing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(id) = self.id.take() {
+                self.lim.close(id)
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Closing {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn graceful_close_completes_inflight_io() {
+        future::lazy(|| {
+            let (lim, _clock) = Limiter::with_manual_clock(100);
+            let mut w = Limited::new(Closing { lim: lim.clone(), id: None }, lim.clone()).unwrap();
+            assert_eq!(10, w.write(&[0; 10]).unwrap());
+            w.io.id = Some(w.id);
+            // the write in progress completes
+            assert_eq!(10, w.write(&[0; 10]).unwrap());
+            assert_eq!(20, lim.total_transferred());
+            // but nothing more is granted
+            assert!(w.poll_write_ready().unwrap().is_ready());
+            assert_eq!(0, w.write(&[0; 10]).unwrap());
+            assert_eq!(20, lim.total_transferred());
+            assert_eq!(80, lim.available());
+            let mut r = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            r.close_gracefully();
+            assert_eq!(0, r.read(&mut [0; 10]).unwrap());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_and_writes_take_turns() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let pipe = Pipe::default();
+            pipe.0.lock().0.extend(&[1; 10_000]);
+            let mut io = Limited::new(pipe, lim).unwrap();
+            let (mut read, mut written) = (0, 0);
+            for _ in 0 .. 10 {
+                // a busy read loop does not starve writes
+                while let Ok(n) = io.read(&mut [0; 1000]) {
+                    read += n
+                }
+                while let Ok(n) = io.write(&[0; 1000]) {
+                    written += n
+                }
+                clock.tick();
+            }
+            assert!(read >= 300 && written >= 200, "read = {}, written = {}", read, written);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn capacity_set_aside_goes_to_the_other_direction() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let pipe = Pipe::default();
+            pipe.0.lock().0.extend(&[1; 10_000]);
+            let (mut r, mut w) = Limited::new(pipe, lim.clone()).unwrap().split();
+            let mut other = Limited::new(io::repeat(1), lim.clone()).unwrap();
+            let counts = Arc::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+            let c = counts.clone();
+            let reader = future::poll_fn(move || loop {
+                match r.read(&mut [0; 1000]) {
+                    Ok(n) => c[0].fetch_add(n, Ordering::SeqCst),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok::<Async<()>, ()>(Async::NotReady),
+                    Err(e) => panic!("{}", e)
+                };
+            });
+            let c = counts.clone();
+            let writer = future::poll_fn(move || loop {
+                match w.write(&[0; 1000]) {
+                    Ok(n) => c[1].fetch_add(n, Ordering::SeqCst),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok::<Async<()>, ()>(Async::NotReady),
+                    Err(e) => panic!("{}", e)
+                };
+            });
+            let mut tasks: [executor::Spawn<Box<dyn Future<Item = (), Error = ()>>>; 2] =
+                [executor::spawn(Box::new(reader)), executor::spawn(Box::new(writer))];
+            let wakeups = Arc::new(Wakeups::default());
+            let handle = executor::NotifyHandle::from(wakeups.clone());
+            let mut run = |id: usize| {
+                // a task runs if it has been notified
+                let woken = wakeups.0.lock().contains(&id);
+                wakeups.0.lock().retain(|&i| i != id);
+                if woken {
+                    assert!(tasks[id].poll_future_notify(&handle, id).unwrap().is_not_ready())
+                }
+            };
+            wakeups.0.lock().extend(&[0, 1]);
+            for window in 0 .. 10 {
+                let before = (counts[0].load(Ordering::SeqCst), counts[1].load(Ordering::SeqCst));
+                run(0);
+                // a greedy part does not take what has been set aside
+                let mut n = 0;
+                while let Ok(k) = other.read(&mut [0; 1000]) {
+                    n += k
+                }
+                run(1);
+                run(0);
+                let read = counts[0].load(Ordering::SeqCst) - before.0;
+                let written = counts[1].load(Ordering::SeqCst) - before.1;
+                if window == 0 {
+                    assert_eq!((100, 0), (read, written))
+                } else {
+                    // the reader is throttled to make room for the writer
+                    assert!(read >= 74, "read = {}", read);
+                    assert_eq!(25, written);
+                    assert!(n <= 1, "other = {}", n)
+                }
+                clock.tick();
+            }
+            // the writer stops asking and the reader no longer gives way to it
+            for window in 0 .. 3 {
+                let before = counts[0].load(Ordering::SeqCst);
+                run(0);
+                let mut n = 0;
+                while let Ok(k) = other.read(&mut [0; 1000]) {
+                    n += k
+                }
+                let read = counts[0].load(Ordering::SeqCst) - before;
+                // once more, a quarter is set aside and expires unused
+                let aside = if window == 0 { 25 } else { 0 };
+                assert!(read + n + aside >= 99, "read = {}", read);
+                clock.tick();
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn guaranteed_rate_holds_under_contention() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut others = (0 .. 10).map(|_| Limited::new(io::repeat(1), lim.clone()).unwrap()).collect::<Vec<_>>();
+            let mut g = Limited::new_guaranteed(io::repeat(1), lim.clone(), 40).unwrap();
+            assert!(matches!(Limited::new_guaranteed(io::empty(), lim.clone(), 61), Err(Error::Overcommitted)));
+            assert_eq!(11, lim.parts());
+            for _ in 0 .. 5 {
+                // best-effort parts greedily go first
+                let mut served = 0;
+                for p in &mut others {
+                    while let Ok(n) = p.read(&mut [0; 100]) {
+                        served += n
+                    }
+                }
+                assert_eq!(60, served);
+                let mut n = 0;
+                while let Ok(k) = g.read(&mut [0; 100]) {
+                    n += k
+                }
+                assert_eq!(40, n);
+                clock.tick();
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
     }
 }
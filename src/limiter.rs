@@ -8,148 +8,1574 @@
 // at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
 // at https://opensource.org/licenses/MIT.
 
-use crate::{algorithms::{bucket::Bucket, Id, Token}, error::{Error, Result}};
-use futures::{prelude::*, task::{self, Task}};
-use log::error;
+use crate::{
+    algorithms::{bucket::{Bucket, PartStats, Refill}, leaky::LeakyBucket, ContinuousRefill, Id, RateAlgorithm, Token, TokenBucket},
+    builder::{LimiterBuilder, OnTick, TimerFailure},
+    clock::Clock,
+    error::{Error, Result, ThrottleReason},
+    limited::{Limited, Transfer},
+    tasks::{SharedTasks, Tasks, Waiter},
+    throttle::Throttled
+};
+use futures::{future, prelude::*, task};
+use log::{debug, error, trace};
 use parking_lot::Mutex;
 use std::{
-    collections::HashMap,
-    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc},
+    cmp::{max, min},
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    ptr,
+    sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Condvar, Weak},
+    thread,
     time::{Duration, Instant}
 };
 use tokio_executor::Executor;
-use tokio_timer::Interval;
-
-type Tasks = Arc<Mutex<HashMap<Id, Task>>>;
+use tokio_io::{io::{ReadHalf, WriteHalf}, AsyncRead, AsyncWrite};
+use tokio_timer::Delay;
 
 /// A `Limiter` maintains rate-limiting invariants over a set
 /// of `Limited` resources.
 #[derive(Clone, Debug)]
 pub struct Limiter {
     bucket: Arc<Bucket>,
-    tasks: Tasks,
-    error: Arc<AtomicBool>
+    tasks: Arc<SharedTasks>,
+    error: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>, // the timer is gone, without having failed
+    clock: Arc<AtomicUsize>, // index of the current time window
+    interval: Duration, // length of a time window
+    credit: Arc<Mutex<Credit>>, // capacity to make available on each tick
+    started: Arc<Mutex<Instant>>, // start of the current time window
+    time: Arc<dyn Clock>, // clock to measure the time within a time window
+    derived: Arc<Mutex<Vec<Derived>>>, // limiters sharing the same timer
+    parent: Option<Arc<Parent>>, // part of the limiter a child limiter borrows from
+    on_failure: TimerFailure, // what to do once the timer has failed
+    throttle_kind: io::ErrorKind, // the kind of I/O error of throttled reads and writes
+    coalesce: usize, // the minimum size of reads of `Limited` resources
+    thread: Option<Arc<TimerThread>> // the thread running the timer, if any
 }
 
 impl Limiter {
     /// Create a new limiter which caps the transfer rate to the given
     /// maximum of bytes per second.
     pub fn new<E: Executor>(e: &mut E, max: usize) -> Result<Limiter> {
-        let bucket = Arc::new(Bucket::new(max));
-        let clock = Arc::new(AtomicUsize::new(0));
-        let tasks = Arc::new(Mutex::new(HashMap::<Id, Task>::new()));
-        let error = Arc::new(AtomicBool::new(false));
-        let limiter = Limiter {
-            bucket: bucket.clone(),
-            tasks: tasks.clone(),
-            error: error.clone()
-        };
-        let timer = Interval::new(Instant::now(), Duration::from_secs(1))
-            .for_each(move |_| {
-                bucket.reset(clock.fetch_add(1, Ordering::Relaxed));
-                let mut tt = tasks.lock();
-                for t in tt.drain() {
-                    t.1.notify()
+        LimiterBuilder::new(max).build(e)
+    }
+
+    /// Create a new limiter which caps the transfer rate to the given
+    /// maximum of bytes per second and works as a leaky bucket, i.e. paces
+    /// transfers evenly across every second instead of making the capacity
+    /// of a whole second available at once, see `LeakyBucket`.
+    pub fn leaky<E: Executor>(e: &mut E, max: usize) -> Result<Limiter> {
+        LimiterBuilder::new(max).algorithm(Box::new(LeakyBucket::default())).build(e)
+    }
+
+    /// Create a new limiter which caps the transfer rate to the given
+    /// maximum of bytes per second, together with the timer future which
+    /// drives it. The caller is responsible for running the future, which
+    /// fails if the timer fails, e.g. because it has been shut down.
+    pub fn new_unspawned(max: usize) -> (Limiter, impl Future<Item = (), Error = ()>) {
+        LimiterBuilder::new(max).build_unspawned()
+    }
+
+    /// Create a new limiter which caps the transfer rate to the given
+    /// maximum of bytes per second and is driven by a timer on a dedicated
+    /// thread instead of a future on an executor, e.g. if the executor is
+    /// too busy to run the timer on time. No runtime is needed to drive it.
+    ///
+    /// The thread exits as soon as all handles of the limiter are gone.
+    pub fn new_threaded(max: usize) -> Result<Limiter> {
+        LimiterBuilder::new(max).build_threaded()
+    }
+
+    /// Create a new builder to configure a limiter which caps the transfer
+    /// rate to the given maximum of bytes per second.
+    pub fn builder(max: usize) -> LimiterBuilder {
+        LimiterBuilder::new(max)
+    }
+
+    /// Create a new limiter which caps the transfer rate to the given
+    /// maximum of bytes per second, without a background timer.
+    ///
+    /// Time only advances when `ClockHandle::tick` is called, which makes
+    /// rate limiting deterministic, e.g. for tests.
+    pub fn with_manual_clock(max: usize) -> (Limiter, ClockHandle) {
+        LimiterBuilder::new(max).build_with_manual_clock()
+    }
+
+    /// Create a new limiter which caps the transfer rate to the given
+    /// maximum of bytes per second and starts a new time window on every
+    /// item of the given stream, e.g. an existing `Interval`.
+    ///
+    /// Instead of being spawned onto an executor, the future which drives
+    /// the limiter is returned to the caller.
+    pub fn from_interval<S>(stream: S, max: usize) -> (Limiter, impl Future<Item = (), Error = ()>)
+    where
+        S: Stream<Item = Instant>,
+        S::Error: fmt::Display
+    {
+        LimiterBuilder::new(max).build_from_interval(stream)
+    }
+
+    pub(crate) fn from_builder<E: Executor>(e: &mut E, b: LimiterBuilder) -> Result<Limiter> {
+        let (limiter, timer) = Limiter::unspawned(b);
+        e.spawn(Box::new(timer))?;
+        Ok(limiter)
+    }
+
+    /// Create a limiter and spawn a thread which drives it.
+    ///
+    /// The thread waits for the next wake-up on a condition variable, which
+    /// is signalled by `notify_all` and once the last handle of the limiter
+    /// is dropped, so that the thread exits right away.
+    pub(crate) fn threaded(b: LimiterBuilder) -> Result<Limiter> {
+        let interval = b.interval();
+        let (mut limiter, mut ticker) = Limiter::unclocked(b);
+        let wakeup = Arc::new(Wakeup::default());
+        limiter.thread = Some(Arc::new(TimerThread(wakeup.clone())));
+        thread::Builder::new()
+            .name("aio-limited-timer".into())
+            .spawn(move || {
+                let mut next = Instant::now() + interval;
+                loop {
+                    let woken = wakeup.wait_until(ticker.next_wakeup(Instant::now(), next));
+                    if ticker.is_orphaned() {
+                        break
+                    }
+                    if woken {
+                        continue
+                    }
+                    if Instant::now() < next {
+                        ticker.wake();
+                        continue
+                    }
+                    ticker.restart();
+                    ticker.tick();
+                    // like an `Interval`, catch up with ticks which are late
+                    next += interval
+                }
+            })?;
+        Ok(limiter)
+    }
+
+    /// Create a limiter together with the `Delay`-driven future which
+    /// drives it.
+    pub(crate) fn unspawned(b: LimiterBuilder) -> (Limiter, impl Future<Item = (), Error = ()>) {
+        let interval = b.interval();
+        let (limiter, mut ticker) = Limiter::unclocked(b);
+        let guard = TimerGuard(Some(limiter.downgrade()));
+        let mut next = Instant::now() + interval;
+        let mut delay = Delay::new(ticker.next_wakeup(Instant::now(), next));
+        let timer = future::poll_fn(move || loop {
+            match delay.poll() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    error!("timer error: {}", e);
+                    guard.fail(false);
+                    return Err(())
                 }
+            }
+            let now = Instant::now();
+            if now < next {
+                ticker.wake()
+            } else {
+                ticker.restart();
+                ticker.tick();
+                // like an `Interval`, catch up with ticks which are late
+                next += interval
+            }
+            delay.reset(ticker.next_wakeup(now, next))
+        });
+        (limiter, timer)
+    }
+
+    /// Create a limiter together with the future which drives it by
+    /// starting a new time window on every item of the given stream.
+    pub(crate) fn driven_by<S>(b: LimiterBuilder, stream: S) -> (Limiter, impl Future<Item = (), Error = ()>)
+    where
+        S: Stream<Item = Instant>,
+        S::Error: fmt::Display
+    {
+        let (limiter, mut ticker) = Limiter::unclocked(b);
+        let mut guard = TimerGuard(Some(limiter.downgrade()));
+        let timer = stream
+            .for_each(move |_| {
+                ticker.restart();
+                ticker.tick();
                 Ok(())
             })
-            .map_err(move |e| {
-                error!("interval error: {}", e);
-                error.store(true, Ordering::Release)
+            .then(move |result| match result {
+                // the stream has ended, which is up to the caller
+                Ok(()) => {
+                    guard.disarm();
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("interval error: {}", e);
+                    guard.fail(false);
+                    Err(())
+                }
             });
-        e.spawn(Box::new(timer))?;
-        Ok(limiter)
+        (limiter, timer)
     }
 
-    pub(crate) fn get(&self, id: Id, hint: usize) -> Result<Token> {
-        if self.error.load(Ordering::Acquire) {
-            return Err(Error::TimerError)
+    /// Create a limiter together with the `Ticker` which drives it.
+    pub(crate) fn unclocked(b: LimiterBuilder) -> (Limiter, Ticker) {
+        let interval = b.interval();
+        let mut credit = Credit::new(b.amount, b.window, interval);
+        let algorithm = match &b.algorithm {
+            Some(a) => a.clone(),
+            None if b.continuous => Arc::new(ContinuousRefill),
+            None => Arc::new(TokenBucket)
+        };
+        let mut bucket = Bucket::new(credit.maximum())
+            .with_soft_start(b.soft_start)
+            .with_max_parts(b.max_parts)
+            .with_expected_parts(b.expected_parts)
+            .with_refill(Refill { clock: b.clock.clone(), amount: b.amount, window: b.window })
+            .with_algorithm(algorithm);
+        if let Some(d) = b.idle_decay {
+            bucket = bucket.with_idle_decay(d)
+        }
+        let bucket = Arc::new(bucket);
+        bucket.reset(0, credit.next());
+        let credit = Arc::new(Mutex::new(credit));
+        let tasks = Arc::new(SharedTasks::new(Tasks::with_backoff(b.backoff).with_expected_parts(b.expected_parts)));
+        let error = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let clock = Arc::new(AtomicUsize::new(0));
+        let started = Arc::new(Mutex::new(b.clock.now()));
+        let derived = Arc::new(Mutex::new(Vec::new()));
+        let limiter = Limiter {
+            bucket,
+            tasks,
+            error,
+            stopped,
+            clock,
+            interval,
+            credit,
+            started,
+            time: b.clock.clone(),
+            derived,
+            parent: None,
+            on_failure: b.on_timer_failure,
+            throttle_kind: b.throttle_error_kind,
+            coalesce: b.coalesce,
+            thread: None
+        };
+        let ticker = Ticker {
+            limiter: limiter.clone(),
+            on_tick: b.on_tick
+        };
+        (limiter, ticker)
+    }
+
+    /// Create a limiter without a background timer whose capacity of
+    /// `usize::MAX` bytes is never exhausted in practice.
+    pub(crate) fn unlimited() -> Limiter {
+        Limiter::unclocked(LimiterBuilder::new(usize::MAX)).0
+    }
+
+    /// Create a new limiter which caps the transfer rate to the given
+    /// maximum of bytes per second and is driven by the same timer as
+    /// this one, i.e. both start new time windows on the same tick.
+    pub fn clone_with_rate(&self, max: usize) -> Limiter {
+        let mut credit = Credit::new(max, Duration::from_secs(1), self.interval);
+        let mut bucket = Bucket::new(credit.maximum())
+            .with_soft_start(self.bucket.soft_start())
+            .with_max_parts(self.bucket.max_parts())
+            .with_refill(Refill { clock: self.time.clone(), amount: max, window: Duration::from_secs(1) })
+            .with_algorithm(self.bucket.algorithm());
+        if let Some(d) = self.bucket.idle_decay() {
+            bucket = bucket.with_idle_decay(d)
+        }
+        let bucket = Arc::new(bucket);
+        bucket.reset(self.tick(), credit.next());
+        let credit = Arc::new(Mutex::new(credit));
+        let tasks = Arc::new(SharedTasks::new(Tasks::with_backoff(self.tasks.max_skips())));
+        self.derived.lock().push(Derived {
+            bucket: Arc::downgrade(&bucket),
+            tasks: Arc::downgrade(&tasks),
+            credit: credit.clone()
+        });
+        Limiter {
+            bucket,
+            tasks,
+            error: self.error.clone(),
+            stopped: self.stopped.clone(),
+            clock: self.clock.clone(),
+            interval: self.interval,
+            credit,
+            started: self.started.clone(),
+            time: self.time.clone(),
+            derived: self.derived.clone(),
+            parent: None,
+            on_failure: self.on_failure,
+            throttle_kind: self.throttle_kind,
+            coalesce: self.coalesce,
+            thread: self.thread.clone()
+        }
+    }
+
+    /// Create a child limiter which caps the transfer rate to the given
+    /// maximum of bytes per second and is driven by the same timer as
+    /// this one.
+    ///
+    /// The child is registered as a single part of this limiter and every
+    /// byte it hands out is also drawn from this limiter's capacity, so
+    /// children never exceed their parent's rate together. A child whose
+    /// siblings are idle borrows their unused capacity, up to its own
+    /// maximum. Capacity given back to the child is given back to this
+    /// limiter as well.
+    pub fn child(&self, max: usize) -> Result<Limiter> {
+        let id = self.register()?;
+        let parent = Parent { limiter: self.clone(), id };
+        Ok(Limiter { parent: Some(Arc::new(parent)), .. self.clone_with_rate(max) })
+    }
+
+    /// Run the given future only once `cost` bytes of capacity have been
+    /// acquired for it, e.g. for transfers which do not use `Limited`.
+    ///
+    /// The future is registered as a separate part of this limiter.
+    pub fn throttle<F: Future>(&self, future: F, cost: usize) -> Result<Throttled<F>> {
+        Throttled::new(future, self.clone(), cost)
+    }
+
+    /// Rate-limit a borrowed resource, e.g. for a scoped operation on a
+    /// connection which is owned elsewhere. The returned resource is a new
+    /// part of this limiter until it is dropped, which ends the borrow.
+    pub fn wrap_mut<'a, T>(&self, io: &'a mut T) -> Result<Limited<&'a mut T>> {
+        Limited::new(io, self.clone())
+    }
+
+    /// Copy all bytes of `reader` to `writer` at this limiter's rate and
+    /// resolve to the number of bytes copied.
+    ///
+    /// The writer is wrapped in a `Limited` resource for the duration of
+    /// the transfer, i.e. it is a new part of this limiter until the
+    /// returned future completes or is dropped.
+    pub fn transfer<R, W>(&self, reader: R, writer: W) -> impl Future<Item = u64, Error = Error>
+    where
+        R: AsyncRead,
+        W: AsyncWrite
+    {
+        future::result(Limited::new(writer, self.clone())).and_then(|w| Transfer::new(reader, w))
+    }
+
+    /// Split a duplex resource into its read and write halves, limited by
+    /// `reader` and `writer` respectively.
+    ///
+    /// If the write half can not be registered, the registration of the
+    /// read half is rolled back.
+    #[allow(clippy::type_complexity)]
+    pub fn split_limited<T>(io: T, reader: &Limiter, writer: &Limiter)
+        -> Result<(Limited<ReadHalf<T>>, Limited<WriteHalf<T>>)>
+    where
+        T: AsyncRead + AsyncWrite
+    {
+        let (r, w) = io.split();
+        let r = Limited::new(r, reader.clone())?;
+        // on error, dropping `r` deregisters its part again
+        let w = Limited::new(w, writer.clone())?;
+        Ok((r, w))
+    }
+
+    /// Create a handle to this limiter which does not keep it alive.
+    pub fn downgrade(&self) -> WeakLimiter {
+        WeakLimiter {
+            bucket: Arc::downgrade(&self.bucket),
+            tasks: Arc::downgrade(&self.tasks),
+            error: Arc::downgrade(&self.error),
+            stopped: Arc::downgrade(&self.stopped),
+            clock: Arc::downgrade(&self.clock),
+            interval: self.interval,
+            credit: Arc::downgrade(&self.credit),
+            started: Arc::downgrade(&self.started),
+            time: self.time.clone(),
+            derived: Arc::downgrade(&self.derived),
+            parent: self.parent.as_ref().map(Arc::downgrade),
+            on_failure: self.on_failure,
+            throttle_kind: self.throttle_kind,
+            coalesce: self.coalesce,
+            thread: self.thread.as_ref().map(Arc::downgrade)
+        }
+    }
+
+    /// Split a duplex resource into its read and write halves, which share
+    /// the capacity of this limiter in the ratio `read_weight : write_weight`.
+    ///
+    /// Each half gets at most its proportion of a time window's capacity,
+    /// even if the other half is idle, so the ratio holds under load while
+    /// both together never exceed the rate.
+    #[allow(clippy::type_complexity)]
+    pub fn duplex_weighted<T>(&self, io: T, read_weight: u32, write_weight: u32)
+        -> Result<(Limited<ReadHalf<T>>, Limited<WriteHalf<T>>)>
+    where
+        T: AsyncRead + AsyncWrite
+    {
+        let (r, w) = Limiter::split_limited(io, self, self)?;
+        let total = u64::from(read_weight) + u64::from(write_weight);
+        for (id, weight) in [(r.id(), read_weight), (w.id(), write_weight)] {
+            let share = self.rate() as u64 * u64::from(weight) / max(1, total);
+            self.bucket.set_weight(id, weight);
+            // every half with a non-zero weight makes progress
+            self.bucket.set_cap(id, Some(max(u64::from(weight > 0), share) as usize))
+        }
+        Ok((r, w))
+    }
+
+    /// Check if the background timer has failed or is gone. If so, this
+    /// fails with `Error::TimerError` or `Error::LimiterShutdown`
+    /// respectively, unless the limiter fails open.
+    fn timer_failed(&self) -> Result<bool> {
+        if !self.error.load(Ordering::Acquire) {
+            return Ok(false)
+        }
+        match self.on_failure {
+            TimerFailure::FailOpen => Ok(true),
+            TimerFailure::FailClosed if self.stopped.load(Ordering::Acquire) => Err(Error::LimiterShutdown),
+            TimerFailure::FailClosed => Err(Error::TimerError)
+        }
+    }
+
+    /// Check that the background timer has not failed and is still running,
+    /// i.e. that the future driving it has not been dropped, e.g. because
+    /// its executor has shut down. An unhealthy limiter fails all operations
+    /// with `Error::TimerError` or `Error::LimiterShutdown` and should be
+    /// replaced.
+    pub fn is_healthy(&self) -> bool {
+        !self.error.load(Ordering::Acquire)
+    }
+
+    /// The index of the current time window, i.e. the number of refills
+    /// which have happened since this limiter was created.
+    pub fn tick(&self) -> usize {
+        self.clock.load(Ordering::Relaxed)
+    }
+
+    /// The time until the next time window starts and new capacity is made
+    /// available, e.g. to tell a throttled client when to retry. This is
+    /// zero if the timer is late.
+    pub fn time_to_next_tick(&self) -> Duration {
+        let elapsed = self.time.now().saturating_duration_since(*self.started.lock());
+        self.interval.saturating_sub(elapsed)
+    }
+
+    /// Change the rate to `target` bytes per second gradually, in equal
+    /// steps on every tick during the given duration, instead of all at
+    /// once, e.g. to lower the rate without stalling transfers in progress.
+    /// A ramp which is still in progress is replaced, starting from the
+    /// rate reached so far.
+    ///
+    /// If the limiter is refilled continuously, only the maximum capacity
+    /// follows the ramp, the rate at which it is refilled does not change.
+    pub fn ramp_rate(&self, target: usize, over: Duration) {
+        let steps = over.as_nanos() / self.interval.as_nanos();
+        self.credit.lock().ramp(target, self.interval, steps)
+    }
+
+    /// Change the algorithm which decides when capacity becomes available,
+    /// e.g. to pace transfers evenly under high load, without dropping any
+    /// resources.
+    ///
+    /// The swap happens under the lock of the capacity. Registered parts,
+    /// their shares and the capacity handed out during the current time
+    /// window are left as they are, and tokens acquired before can be
+    /// released as usual. Limiters derived from this one keep their
+    /// algorithm. The background timer wakes up as often as the new
+    /// algorithm asks for from the next tick on, at the latest.
+    pub fn set_algorithm(&self, algo: Box<dyn RateAlgorithm>) {
+        debug!("algorithm changed to {:?}", algo);
+        self.bucket.set_algorithm(Arc::from(algo))
+    }
+
+    /// The number of currently registered parts, i.e. `Limited` resources.
+    pub fn parts(&self) -> usize {
+        self.bucket.parts()
+    }
+
+    /// Change the maximum number of parts, e.g. to accept more connections
+    /// during a load spike. If the maximum is lowered below the current
+    /// number of parts, existing parts are not affected, but registering
+    /// new parts fails until enough parts have been deregistered.
+    pub fn set_max_parts(&self, max_parts: usize) {
+        self.bucket.set_max_parts(max_parts)
+    }
+
+    /// The maximum number of bytes made available per time window.
+    pub fn rate(&self) -> usize {
+        self.bucket.stats().maximum
+    }
+
+    /// The number of bytes still available during the current time window.
+    pub fn available(&self) -> usize {
+        self.bucket.stats().value
+    }
+
+    /// The total number of bytes handed out since this limiter was created
+    /// or since the last `reset_stats`.
+    pub fn total_transferred(&self) -> u64 {
+        self.bucket.stats().total
+    }
+
+    /// The kind of I/O error with which reads and writes of `Limited`
+    /// resources fail when they are throttled, see
+    /// `LimiterBuilder::throttle_error_kind`.
+    pub fn throttle_error_kind(&self) -> io::ErrorKind {
+        self.throttle_kind
+    }
+
+    /// The minimum size of reads of `Limited` resources, see
+    /// `LimiterBuilder::coalesce`.
+    pub(crate) fn coalesce(&self) -> usize {
+        self.coalesce
+    }
+
+    /// The I/O error of a read or write of the given part which is
+    /// throttled by this limiter.
+    pub(crate) fn throttled(&self, id: Id) -> io::Error {
+        self.throttled_by(self.bucket.throttle_reason(id))
+    }
+
+    /// The I/O error of a read or write throttled for the given reason.
+    pub(crate) fn throttled_by(&self, reason: ThrottleReason) -> io::Error {
+        io::Error::new(self.throttle_kind, reason)
+    }
+
+    /// The number of bytes handed out during the most recently completed
+    /// time window, i.e. the rate actually achieved, as opposed to the
+    /// configured `rate`.
+    pub fn achieved_rate(&self) -> usize {
+        self.bucket.stats().served
+    }
+
+    /// Estimate how long it takes a single part to transfer `n` bytes at
+    /// its share of the current rate, given the current number of parts
+    /// (at least one).
+    ///
+    /// This is only an estimate: it assumes that the number of parts and
+    /// their demand stay the same for the duration of the transfer.
+    pub fn time_to_send(&self, n: usize) -> Duration {
+        let stats = self.bucket.stats();
+        let nanos = n as u128 * self.interval.as_nanos() * max(1, stats.parts) as u128
+            / max(1, stats.maximum) as u128;
+        Duration::from_nanos(nanos.min(u128::from(u64::MAX)) as u64)
+    }
+
+    /// The number of times a request for capacity has been turned away
+    /// since this limiter was created or since the last `reset_stats`. A
+    /// high count relative to the traffic indicates an overloaded limiter.
+    pub fn throttle_count(&self) -> u64 {
+        self.bucket.throttled()
+    }
+
+    /// Set the statistics counters, i.e. `total_transferred`,
+    /// `throttle_count` and, with the `wait-stats` feature, `wait_stats`,
+    /// back to zero, e.g. to report them per reporting interval.
+    ///
+    /// This is best-effort: the counters are reset one after the other, so
+    /// updates which happen concurrently may be lost or be counted in one
+    /// counter but not in another. Capacity handed out before the reset and
+    /// given back afterwards is not subtracted from the new total.
+    pub fn reset_stats(&self) {
+        self.bucket.reset_stats();
+        #[cfg(feature = "wait-stats")]
+        self.tasks.reset_wait_stats()
+    }
+
+    /// Get a consistent view of this limiter's state.
+    pub fn snapshot(&self) -> LimiterSnapshot {
+        let stats = self.bucket.stats();
+        LimiterSnapshot {
+            rate: stats.maximum,
+            available: stats.value,
+            parts: stats.parts,
+            transferred: stats.total,
+            tick: stats.index,
+            healthy: self.is_healthy()
+        }
+    }
+
+    /// Acquire up to `hint` bytes of capacity for the given part, e.g. to
+    /// do rate-limited I/O without a `Limited` wrapper.
+    ///
+    /// Returns `Error::NoCapacity` if the part's share of the current time
+    /// window is exhausted. Capacity which has been used is marked as such
+    /// with `Token::consume`. Unused capacity is given back with
+    /// `Limiter::release`, or when the token is dropped.
+    pub fn acquire(&self, id: Id, hint: usize) -> Result<Token> {
+        if self.timer_failed()? {
+            return Ok(self.bucket.full(id))
+        }
+        let mut t = self.bucket.get(id, hint).map_err(|e| {
+            if let Error::NoCapacity = e {
+                trace!("{}: no capacity left", label(&self.bucket, id));
+                #[cfg(feature = "tracing")]
+                tracing::trace!(part = %id, "no capacity left")
+            }
+            e
+        })?;
+        t.attach(&self.bucket);
+        if let Some(p) = &self.parent {
+            match p.limiter.acquire(p.id, t.get()) {
+                Ok(borrowed) => {
+                    self.bucket.release(t.split_off(borrowed.get()));
+                    t.borrowed(borrowed)
+                }
+                Err(e) => {
+                    trace!("{}: no capacity left in parent", label(&self.bucket, id));
+                    self.bucket.release(t);
+                    return Err(e)
+                }
+            }
+        }
+        // a remnant does not count as a turn to be served
+        if !t.is_remnant() {
+            self.tasks.served(id)
+        }
+        Ok(t)
+    }
+
+    /// Reserve `total` bytes of capacity for the given part, which are
+    /// handed out in chunks across time windows as the part's share permits,
+    /// e.g. to write a frame larger than the share of a single time window
+    /// without handling partial grants.
+    pub fn reserve(&self, id: Id, total: usize) -> Reservation {
+        Reservation { lim: self.clone(), id, remaining: total }
+    }
+
+    /// Acquire the largest chunks of capacity available to the given part
+    /// until the current time window is exhausted.
+    ///
+    /// Unlike `Limited`, the part is not enqueued to be notified when new
+    /// capacity becomes available.
+    pub fn drain_chunks(&self, id: Id) -> impl Iterator<Item = Token> + '_ {
+        std::iter::from_fn(move || self.acquire(id, usize::MAX).ok())
+    }
+
+    /// The number of bytes `acquire` would currently grant to the given part.
+    pub(crate) fn peek(&self, id: Id) -> Result<usize> {
+        if self.timer_failed()? {
+            return Ok(self.rate())
+        }
+        let n = self.bucket.peek(id)?;
+        match &self.parent {
+            Some(p) => Ok(min(n, p.limiter.peek(p.id)?)),
+            None => Ok(n)
         }
-        self.bucket.get(id, hint)
     }
 
-    pub(crate) fn release(&self, t: Token) {
+    /// The number of bytes the given part would get from `acquire` right
+    /// now, taking its weight, priority, guaranteed rate and cap into
+    /// account, e.g. to decide how much to batch. Unlike `available`, which
+    /// is the capacity left for all parts together, this is the part's own
+    /// share of it. Closed parts get nothing.
+    pub fn share_for(&self, id: Id) -> usize {
+        self.peek(id).unwrap_or(0)
+    }
+
+    /// Get the state of the given part, if it is registered.
+    pub fn part_stats(&self, id: Id) -> Option<PartStats> {
+        self.bucket.part_stats(id)
+    }
+
+    /// Give back the (unused quantity of the) given token, which makes it
+    /// available again to all parts. Tokens acquired during a previous
+    /// time window are ignored.
+    pub fn release(&self, t: Token) {
+        if self.error.load(Ordering::Acquire) {
+            // the capacity is not refilled any more
+            t.consume();
+            return
+        }
+        // a token borrowed from the parent is given back when `t` is dropped
         self.bucket.release(t)
     }
 
     pub(crate) fn enqueue(&self, id: Id) -> Result<()> {
-        if self.error.load(Ordering::Acquire) {
-            return Err(Error::TimerError)
+        self.enqueue_in(id, 0)
+    }
+
+    /// Like `enqueue`, but in the given slot, so that tasks waiting on
+    /// behalf of the same part in other slots are not replaced.
+    pub(crate) fn enqueue_in(&self, id: Id, slot: usize) -> Result<()> {
+        self.wait(id, slot, Waiter::Task(task::current()))
+    }
+
+    /// Like `enqueue_in`, but for a `std::future` task with the given waker.
+    #[cfg(feature = "compat")]
+    pub(crate) fn enqueue_waker(&self, id: Id, slot: usize, waker: std::task::Waker) -> Result<()> {
+        self.wait(id, slot, Waiter::Waker(waker))
+    }
+
+    /// Notify the task of the given part waiting in the given slot right
+    /// away, if any, e.g. once capacity has been set aside for it.
+    pub(crate) fn wake(&self, id: Id, slot: usize) {
+        if let Some(t) = self.tasks.take(id, slot) {
+            debug!("{}: notified", label(&self.bucket, id));
+            t.notify()
+        }
+    }
+
+    fn wait(&self, id: Id, slot: usize, waiter: Waiter) -> Result<()> {
+        if self.timer_failed()? {
+            // nothing is limited any more
+            waiter.notify();
+            return Ok(())
         }
-        self.tasks.lock().insert(id, task::current());
+        self.tasks.insert(id, slot, waiter);
+        self.bucket.set_fair(true);
+        trace!("{}: waiting for capacity", label(&self.bucket, id));
         Ok(())
     }
 
-    pub(crate) fn register(&self) -> Result<Id> {
-        if self.error.load(Ordering::Acquire) {
-            return Err(Error::TimerError)
+    /// Statistics about how long tasks waited for capacity, i.e. the time
+    /// between being enqueued and being notified.
+    #[cfg(feature = "wait-stats")]
+    pub fn wait_stats(&self) -> WaitStats {
+        self.tasks.wait_stats()
+    }
+
+    /// Wait until at least `min` bytes are available during the current time
+    /// window, e.g. before admitting a new connection which would otherwise
+    /// starve right away. `min` is capped at the rate.
+    ///
+    /// The waiting task is notified on every tick, like the tasks of parts
+    /// waiting for capacity, but it is not a part itself and does not take
+    /// any capacity.
+    pub fn block_until_available(&self, min: usize) -> impl Future<Item = (), Error = Error> {
+        Available {
+            lim: self.clone(),
+            id: self.bucket.new_id(),
+            min: std::cmp::min(min, self.rate())
+        }
+    }
+
+    /// A stream of the time indices of the ticks of this limiter's timer,
+    /// starting with the next one, e.g. for schedulers which react to new
+    /// capacity being made available. If the stream is polled less often
+    /// than the timer ticks, ticks are skipped, but the indices are always
+    /// increasing.
+    ///
+    /// Like the tasks of parts waiting for capacity, the task polling the
+    /// stream is notified on every tick. Once the background timer has
+    /// failed, the stream fails with `Error::TimerError`, or ends if the
+    /// limiter fails open.
+    pub fn ticks(&self) -> impl Stream<Item = usize, Error = Error> {
+        Ticks { lim: self.clone(), id: self.bucket.new_id(), seen: self.tick() }
+    }
+
+    /// Measure how many bytes the given part could transfer during the
+    /// given duration, without transferring anything, e.g. for capacity
+    /// planning. The duration is rounded down to whole time windows, but
+    /// is at least the current one.
+    ///
+    /// During every time window, starting with the current one, the part
+    /// acquires all capacity it can get, which is given back right away, so
+    /// other parts are affected only while the probe runs. The future
+    /// resolves to the total acquired.
+    pub fn probe(&self, id: Id, window: Duration) -> impl Future<Item = usize, Error = Error> {
+        let windows = max(1, window.as_nanos() / self.interval.as_nanos()) as u64;
+        let lim = self.clone();
+        future::lazy(move || {
+            let first = lim.sample(id);
+            let ticks = lim.ticks().take(windows - 1);
+            ticks.fold(first, move |total, _| Ok::<_, Error>(total + lim.sample(id)))
+        })
+    }
+
+    /// Acquire all capacity available to the given part during the current
+    /// time window, give it back and return how much it was.
+    fn sample(&self, id: Id) -> usize {
+        let mut tokens = Vec::new();
+        let mut total = 0;
+        // a limiter without parts never runs out of capacity
+        while total < self.rate() {
+            match self.acquire(id, usize::MAX) {
+                Ok(t) => {
+                    total += t.get();
+                    tokens.push(t)
+                }
+                Err(_) => break
+            }
+        }
+        for t in tokens {
+            self.release(t)
+        }
+        total
+    }
+
+    /// The parts which are currently waiting for capacity, longest-waiting
+    /// first, e.g. to find out which resources are throttled.
+    pub fn waiting(&self) -> Vec<Id> {
+        self.tasks.waiting()
+    }
+
+    /// Halt all traffic, e.g. during a maintenance window, without
+    /// deregistering any parts. Until `resume` is called, no capacity is
+    /// handed out and all parts have to wait. The background timer keeps
+    /// running but does not make any capacity available.
+    pub fn pause(&self) {
+        self.bucket.pause()
+    }
+
+    /// Let traffic continue after `pause`, and notify the waiting tasks. If
+    /// time windows have started while paused, capacity is available again
+    /// from the next time window on.
+    pub fn resume(&self) {
+        self.bucket.resume();
+        self.notify_all()
+    }
+
+    /// Whether the limiter is paused, see `Limiter::pause`.
+    pub fn is_paused(&self) -> bool {
+        self.bucket.is_paused()
+    }
+
+    /// Notify all tasks waiting for capacity right away instead of on the
+    /// next tick, e.g. to let them observe a shutdown promptly.
+    pub fn notify_all(&self) {
+        self.bucket.set_fair(false);
+        notify(&self.bucket, self.tasks.drain(true).0);
+        if let Some(t) = &self.thread {
+            t.0.notify()
         }
+    }
+
+    /// Register a new part to acquire capacity for.
+    ///
+    /// The part participates in the division of capacity until it is
+    /// removed with `Limiter::deregister`. Fails with `Error::TooManyParts`
+    /// if the maximum number of parts configured with
+    /// `LimiterBuilder::max_parts` has been reached.
+    pub fn register(&self) -> Result<Id> {
+        self.timer_failed()?;
         self.bucket.add_part()
     }
 
-    pub(crate) fn deregister(&self, id: Id) {
-        self.tasks.lock().remove(&id);
-        self.bucket.remove_part(id)
+    /// Register `n` new parts at once, e.g. for a batch of connections
+    /// admitted to a pool, which can then be wrapped with
+    /// `Limited::from_id`.
+    ///
+    /// Either all or none of the parts are registered. Fails with
+    /// `Error::TooManyParts` if the maximum number of parts would be
+    /// exceeded.
+    pub fn register_many(&self, n: usize) -> Result<Vec<Id>> {
+        self.timer_failed()?;
+        self.bucket.add_parts(n)
+    }
+
+    /// Limit the given part to at most `rate` bytes per second.
+    pub(crate) fn set_cap(&self, id: Id, rate: usize) {
+        let per_window = rate as u128 * self.interval.as_nanos() / Duration::from_secs(1).as_nanos();
+        self.bucket.set_cap(id, Some(max(1, per_window as usize)))
+    }
+
+    /// Guarantee the given part at least `rate` bytes per second.
+    pub(crate) fn set_floor(&self, id: Id, rate: usize) -> Result<()> {
+        let per_window = (rate as u128 * self.interval.as_nanos()).div_ceil(Duration::from_secs(1).as_nanos());
+        self.bucket.set_floor(id, per_window as usize)
+    }
+
+    pub(crate) fn set_tag(&self, id: Id, tag: u64) {
+        self.bucket.set_tag(id, tag)
+    }
+
+    pub(crate) fn set_priority(&self, id: Id, priority: u8) {
+        self.bucket.set_priority(id, priority)
+    }
+
+    pub(crate) fn set_overdraft(&self, id: Id, max: usize) {
+        self.bucket.set_overdraft(id, max)
+    }
+
+    pub(crate) fn set_weight(&self, id: Id, weight: u32) {
+        self.bucket.set_weight(id, weight)
+    }
+
+    pub(crate) fn set_paused(&self, id: Id, paused: bool) {
+        self.bucket.set_paused(id, paused)
+    }
+
+    pub(crate) fn close(&self, id: Id) {
+        self.bucket.close(id);
+        trace!("{}: closed", label(&self.bucket, id))
+    }
+
+    /// Remove a part previously added with `Limiter::register`.
+    ///
+    /// Deregistering a part which is not registered has no effect.
+    pub fn deregister(&self, id: Id) {
+        self.tasks.remove(id);
+        if !self.bucket.remove_part(id) {
+            debug!("{}: not registered", id)
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate env_logger;
+/// Limiters are compared by identity: clones of a limiter are equal, while
+/// limiters created separately, including those created with
+/// `clone_with_rate` or `child`, are not, even if they are configured alike.
+impl PartialEq for Limiter {
+    fn eq(&self, other: &Limiter) -> bool {
+        Arc::ptr_eq(&self.bucket, &other.bucket)
+    }
+}
 
-    use log::{info, LevelFilter};
-    use std::{cmp::max, io, str, thread};
-    use crate::limited::Limited;
-    use super::*;
-    use tokio::{
-        self,
-        io::{copy, read_exact},
-        net::{TcpListener, TcpStream},
-        prelude::*,
-        runtime::Runtime,
-        timer::Delay
+impl Eq for Limiter {}
+
+impl Hash for Limiter {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        ptr::hash(&*self.bucket, state)
+    }
+}
+
+/// A handle to a `Limiter` which does not keep it alive, obtained via
+/// `Limiter::downgrade`.
+#[derive(Clone, Debug)]
+pub struct WeakLimiter {
+    bucket: Weak<Bucket>,
+    tasks: Weak<SharedTasks>,
+    error: Weak<AtomicBool>,
+    stopped: Weak<AtomicBool>,
+    clock: Weak<AtomicUsize>,
+    interval: Duration,
+    credit: Weak<Mutex<Credit>>,
+    started: Weak<Mutex<Instant>>,
+    time: Arc<dyn Clock>,
+    derived: Weak<Mutex<Vec<Derived>>>,
+    parent: Option<Weak<Parent>>,
+    on_failure: TimerFailure,
+    throttle_kind: io::ErrorKind,
+    coalesce: usize,
+    thread: Option<Weak<TimerThread>>
+}
+
+impl WeakLimiter {
+    /// Get the `Limiter` back, unless all of its handles (including the
+    /// one of its timer, unless the timer runs on a thread of its own)
+    /// have been dropped.
+    pub fn upgrade(&self) -> Option<Limiter> {
+        Some(Limiter {
+            bucket: self.bucket.upgrade()?,
+            tasks: self.tasks.upgrade()?,
+            error: self.error.upgrade()?,
+            stopped: self.stopped.upgrade()?,
+            clock: self.clock.upgrade()?,
+            interval: self.interval,
+            credit: self.credit.upgrade()?,
+            started: self.started.upgrade()?,
+            time: self.time.clone(),
+            derived: self.derived.upgrade()?,
+            parent: match &self.parent {
+                Some(p) => Some(p.upgrade()?),
+                None => None
+            },
+            on_failure: self.on_failure,
+            throttle_kind: self.throttle_kind,
+            coalesce: self.coalesce,
+            thread: match &self.thread {
+                Some(t) => Some(t.upgrade()?),
+                None => None
+            }
+        })
+    }
+}
+
+/// Make the capacity credited on this tick available for the given time
+/// index and notify the waiting tasks, longest-waiting first.
+fn refill(bucket: &Bucket, tasks: &SharedTasks, credit: &Mutex<Credit>, index: usize) -> TickInfo {
+    let value = {
+        let mut credit = credit.lock();
+        let value = credit.next();
+        bucket.set_maximum(credit.maximum());
+        value
     };
+    let served = bucket.reset(index, value);
+    let (tasks, waiting) = tasks.drain(false);
+    // only tasks which back off are still waiting
+    bucket.set_fair(waiting > tasks.len());
+    notify(bucket, tasks);
+    TickInfo { index, served, waiting }
+}
 
-    fn echo_server(
-        addr: &str,
-        lr: Option<Limiter>,
-        lw: Limiter,
-    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
-        let srv = TcpListener::bind(&addr.parse().unwrap())
-            .unwrap()
-            .incoming()
-            .map_err(|e| error!("accept failed = {:?}", e))
-            .for_each(move |s| {
-                let (r, w) = s.split();
-                let reader = match lr {
-                    Some(ref lim) => {
-                        Box::new(Limited::new(r, lim.clone()).unwrap()) as Box<dyn AsyncRead + Send>
-                    }
-                    None => Box::new(r) as Box<dyn AsyncRead + Send>,
-                };
-                let writer = Limited::new(w, lw.clone()).unwrap();
-                let future = copy(reader, writer)
-                    .map(|_| ())
-                    .map_err(|e| error!("copy failed = {:?}", e));
-                tokio::spawn(future)
-            });
-        Box::new(srv)
+/// Notify the waiting tasks, longest-waiting first, if some capacity is
+/// available.
+fn wake(bucket: &Bucket, tasks: &SharedTasks) {
+    if bucket.stats().value > 0 {
+        notify(bucket, tasks.drain(false).0)
     }
+}
 
-    fn echo_client(addr: &str) -> Box<dyn Future<Item = (), Error = ()> + Send> {
-        let clt = TcpStream::connect(&addr.parse().unwrap())
-            .and_then(|stream| copy(&b"0123456789"[..], stream))
-            .and_then(|(n, _, stream)| read_exact(stream, vec![0; n as usize]))
-            .map(|(_stream, buf)| info!("{:?}", str::from_utf8(&buf)))
-            .map_err(|e| error!("client error: {:?}", e));
-        Box::new(clt)
+/// Notify the given tasks in order.
+fn notify(bucket: &Bucket, tasks: Vec<(Id, Waiter)>) {
+    for (id, t) in tasks {
+        debug!("{}: notified", label(bucket, id));
+        t.notify()
     }
+}
 
-    fn echo_rate_client(addr: &str, size: usize) -> Box<dyn Future<Item = (), Error = ()> + Send> {
-        let clt = TcpStream::connect(&addr.parse().unwrap())
-            .and_then(move |stream| {
-                Delay::new(Instant::now() + Duration::from_secs(1)).then(|_| Ok(stream))
-            })
+/// Label a part in log output by its ID and its tag, if any.
+fn label(bucket: &Bucket, id: Id) -> Label {
+    Label(id, bucket.tag(id))
+}
+
+struct Label(Id, Option<u64>);
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            Some(tag) => write!(f, "{} (tag {})", self.0, tag),
+            None => write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// A limiter created by `Limiter::clone_with_rate`, which is refilled by
+/// the `Ticker` of the limiter it has been derived from.
+#[derive(Debug)]
+struct Derived {
+    bucket: Weak<Bucket>,
+    tasks: Weak<SharedTasks>,
+    credit: Arc<Mutex<Credit>>
+}
+
+/// A future which resolves once a `Limiter` has a given capacity available,
+/// see `Limiter::block_until_available`.
+#[derive(Debug)]
+struct Available {
+    lim: Limiter,
+    id: Id, // the ID under which the task waits
+    min: usize
+}
+
+impl Future for Available {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        // waiting is not a failure to get capacity, so do not back off
+        self.lim.tasks.served(self.id);
+        // enqueue first, so that a tick right after the check is not missed
+        self.lim.enqueue(self.id)?;
+        if self.lim.timer_failed()? || self.lim.available() >= self.min {
+            return Ok(Async::Ready(()))
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for Available {
+    fn drop(&mut self) {
+        self.lim.tasks.remove(self.id)
+    }
+}
+
+/// A stream of the time indices of a `Limiter`'s ticks, see `Limiter::ticks`.
+#[derive(Debug)]
+struct Ticks {
+    lim: Limiter,
+    id: Id, // the ID under which the task waits
+    seen: usize // the most recent time index
+}
+
+impl Stream for Ticks {
+    type Item = usize;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<usize>, Error> {
+        if self.lim.timer_failed()? {
+            return Ok(Async::Ready(None))
+        }
+        // waiting for a tick is not a failure to get capacity
+        self.lim.tasks.served(self.id);
+        self.lim.enqueue(self.id)?;
+        let index = self.lim.tick();
+        if index > self.seen {
+            self.seen = index;
+            return Ok(Async::Ready(Some(index)))
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for Ticks {
+    fn drop(&mut self) {
+        self.lim.tasks.remove(self.id)
+    }
+}
+
+/// Capacity for a given total of bytes, handed out to a part in chunks as
+/// it becomes available, e.g. for a frame which is larger than the part's
+/// share of a time window. Created with `Limiter::reserve`.
+///
+/// Every chunk is a `Token` which is taken from the total and which gives
+/// its quantity back unless it is consumed, like the tokens of
+/// `Limiter::acquire`.
+#[derive(Debug)]
+pub struct Reservation {
+    lim: Limiter,
+    id: Id,
+    remaining: usize // the quantity of the total not handed out yet
+}
+
+impl Reservation {
+    /// Get the next chunk of the total, as much as is available to the
+    /// part right now. If nothing is available, the current task is
+    /// notified once new capacity is. Resolves to `None` once the total
+    /// has been handed out or the part has been closed.
+    pub fn poll_chunk(&mut self) -> Poll<Option<Token>, Error> {
+        if self.remaining == 0 {
+            return Ok(Async::Ready(None))
+        }
+        match self.lim.acquire(self.id, self.remaining) {
+            Ok(t) => {
+                self.remaining -= t.get();
+                Ok(Async::Ready(Some(t)))
+            }
+            Err(Error::NoCapacity) => {
+                self.lim.enqueue(self.id)?;
+                Ok(Async::NotReady)
+            }
+            Err(Error::Closed) => Ok(Async::Ready(None)),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// The quantity of the total which has not been handed out yet.
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+}
+
+impl Stream for Reservation {
+    type Item = Token;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Token>, Error> {
+        self.poll_chunk()
+    }
+}
+
+/// Marks a limiter as failed once its background timer fails, or as shut
+/// down once the future driving the timer is dropped before it completes,
+/// e.g. because its executor has shut down.
+struct TimerGuard(Option<WeakLimiter>);
+
+impl TimerGuard {
+    /// Leave the limiter as it is when dropped.
+    fn disarm(&mut self) {
+        self.0 = None
+    }
+
+    /// Mark the limiter as failed, or as shut down if `stopped` is set.
+    fn fail(&self, stopped: bool) {
+        if let Some(lim) = self.0.as_ref().and_then(WeakLimiter::upgrade) {
+            lim.stopped.store(stopped, Ordering::Release);
+            lim.error.store(true, Ordering::Release);
+            // waiting tasks would never be notified otherwise
+            lim.notify_all()
+        }
+    }
+}
+
+impl Drop for TimerGuard {
+    fn drop(&mut self) {
+        if self.0.as_ref().and_then(WeakLimiter::upgrade).is_some_and(|lim| lim.is_healthy()) {
+            debug!("timer is gone");
+            self.fail(true)
+        }
+    }
+}
+
+/// Wakes up the thread of a threaded timer before its next tick is due.
+#[derive(Debug, Default)]
+struct Wakeup {
+    woken: std::sync::Mutex<bool>,
+    condvar: Condvar
+}
+
+impl Wakeup {
+    fn notify(&self) {
+        *self.woken.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        self.condvar.notify_one()
+    }
+
+    /// Wait until notified or until the deadline has passed, and return
+    /// whether notified.
+    fn wait_until(&self, deadline: Instant) -> bool {
+        let mut woken = self.woken.lock().unwrap_or_else(|e| e.into_inner());
+        while !*woken {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout == Duration::from_secs(0) {
+                return false
+            }
+            woken = self.condvar.wait_timeout(woken, timeout).unwrap_or_else(|e| e.into_inner()).0
+        }
+        *woken = false;
+        true
+    }
+}
+
+/// Shared by the handles of a limiter with a threaded timer, but not by
+/// the timer itself. Once the last handle is gone, the thread is woken up
+/// to exit.
+#[derive(Debug)]
+struct TimerThread(Arc<Wakeup>);
+
+impl Drop for TimerThread {
+    fn drop(&mut self) {
+        self.0.notify()
+    }
+}
+
+/// The part of a parent limiter through which a child limiter draws
+/// capacity. The part is deregistered once the child is gone.
+#[derive(Debug)]
+struct Parent {
+    limiter: Limiter,
+    id: Id
+}
+
+impl Drop for Parent {
+    fn drop(&mut self) {
+        self.limiter.deregister(self.id)
+    }
+}
+
+/// Starts new time windows of a `Limiter`.
+#[derive(Debug)]
+pub(crate) struct Ticker {
+    limiter: Limiter,
+    on_tick: Option<OnTick>
+}
+
+impl Ticker {
+    /// Let the current time window start now.
+    fn restart(&mut self) {
+        *self.limiter.started.lock() = self.limiter.time.now()
+    }
+
+    /// Whether no handles of the limiter and of the limiters sharing its
+    /// timer are left but this one, i.e. ticking has no effect any more.
+    fn is_orphaned(&self) -> bool {
+        Arc::strong_count(&self.limiter.bucket) == 1
+            && self.limiter.derived.lock().iter().all(|d| d.bucket.strong_count() == 0)
+    }
+
+    /// Start the next time window.
+    pub(crate) fn tick(&mut self) -> TickInfo {
+        let index = self.limiter.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        let info = refill(&self.limiter.bucket, &self.limiter.tasks, &self.limiter.credit, index);
+        self.limiter.derived.lock().retain_mut(|d| {
+            match (d.bucket.upgrade(), d.tasks.upgrade()) {
+                (Some(bucket), Some(tasks)) => {
+                    refill(&bucket, &tasks, &d.credit, index);
+                    true
+                }
+                _ => false // all clones of the derived limiter are gone
+            }
+        });
+        if let Some(f) = &self.on_tick {
+            (f.0)(info)
+        }
+        info
+    }
+
+    /// Notify the tasks waiting for capacity of this limiter and of the
+    /// limiters sharing its timer, if some capacity is available, without
+    /// starting a new time window.
+    pub(crate) fn wake(&mut self) {
+        wake(&self.limiter.bucket, &self.limiter.tasks);
+        for d in self.limiter.derived.lock().iter() {
+            if let (Some(bucket), Some(tasks)) = (d.bucket.upgrade(), d.tasks.upgrade()) {
+                wake(&bucket, &tasks)
+            }
+        }
+    }
+
+    /// The instant at which the timer wakes up next, given the start of the
+    /// next time window. As often as the algorithms of this limiter and of
+    /// the limiters sharing its timer ask for, the wake-ups are spread
+    /// evenly across the time window.
+    pub(crate) fn next_wakeup(&self, now: Instant, next: Instant) -> Instant {
+        let derived = self.limiter.derived.lock()
+            .iter()
+            .filter_map(|d| d.bucket.upgrade())
+            .map(|b| b.algorithm().wakeups())
+            .max();
+        let n = max(self.limiter.bucket.algorithm().wakeups(), derived.unwrap_or(1));
+        let step = self.limiter.interval / max(1, n);
+        let left = next.saturating_duration_since(now).as_nanos() / max(1, step.as_nanos());
+        next - step * left as u32
+    }
+}
+
+/// A handle to advance the time of a `Limiter` which has been created
+/// without a background timer.
+#[derive(Debug)]
+pub struct ClockHandle(Ticker);
+
+impl ClockHandle {
+    pub(crate) fn new(t: Ticker) -> ClockHandle {
+        ClockHandle(t)
+    }
+
+    /// Start the next time window, i.e. make new capacity available and
+    /// notify all waiting tasks, just like a tick of the background timer.
+    pub fn tick(&mut self) -> TickInfo {
+        self.0.restart();
+        self.0.tick()
+    }
+
+    /// Notify the tasks waiting for capacity if some is available, without
+    /// starting a new time window, just like the background timer does in
+    /// between ticks if the algorithm asks for it, see `RateAlgorithm::wakeups`.
+    pub fn wake(&mut self) {
+        self.0.wake()
+    }
+
+    /// Start as many time windows as have elapsed according to the
+    /// limiter's clock since the current one started.
+    ///
+    /// Returns the number of time windows started.
+    pub fn catch_up(&mut self) -> usize {
+        let now = self.0.limiter.time.now();
+        let interval = self.0.limiter.interval;
+        let mut n = 0;
+        while now.saturating_duration_since(*self.0.limiter.started.lock()) >= interval {
+            self.0.tick();
+            *self.0.limiter.started.lock() += interval;
+            n += 1
+        }
+        n
+    }
+}
+
+/// Information about a tick of the background timer, i.e. the start of
+/// a new time window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TickInfo {
+    /// The index of the new time window.
+    pub index: usize,
+    /// The number of bytes handed out during the previous time window.
+    pub served: usize,
+    /// The number of tasks which were waiting for capacity.
+    pub waiting: usize,
+}
+
+/// The state of a `Limiter` at some point in time.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LimiterSnapshot {
+    /// The maximum number of bytes made available per time window.
+    pub rate: usize,
+    /// The number of bytes still available during the current time window.
+    pub available: usize,
+    /// The number of registered parts.
+    pub parts: usize,
+    /// The total number of bytes handed out since the limiter was created.
+    pub transferred: u64,
+    /// The index of the current time window.
+    pub tick: usize,
+    /// Whether the background timer is still running.
+    pub healthy: bool,
+}
+
+/// Statistics about the time tasks waited for capacity.
+#[cfg(feature = "wait-stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WaitStats {
+    /// The total number of waits.
+    pub count: u64,
+    /// The median of the most recent waits.
+    pub p50: Duration,
+    /// The 99th percentile of the most recent waits.
+    pub p99: Duration,
+    /// The longest wait.
+    pub max: Duration,
+}
+
+/// Computes the capacity to make available on each tick of the
+/// background timer, such that `amount` is credited per `window`.
+#[derive(Debug)]
+struct Credit {
+    amount: u128, // amount scaled by the tick interval
+    window: u128, // window length in nanoseconds
+    carry: u128, // remainder not yet credited
+    ramp: Option<Ramp>, // gradual change of `amount`
+}
+
+/// A gradual change of the amount credited, in equal steps per tick.
+#[derive(Debug)]
+struct Ramp {
+    from: u128, // amount before the change
+    to: u128, // amount after the change
+    steps: u128, // number of ticks over which to change
+    step: u128, // number of ticks since the change began
+}
+
+impl Credit {
+    fn new(amount: usize, window: Duration, interval: Duration) -> Credit {
+        Credit {
+            amount: amount as u128 * interval.as_nanos(),
+            window: window.as_nanos(),
+            carry: 0,
+            ramp: None
+        }
+    }
+
+    /// Change the amount credited per second to `rate`, in `steps` equal
+    /// steps on the next ticks, starting from the current amount.
+    fn ramp(&mut self, rate: usize, interval: Duration, steps: u128) {
+        let to = rate as u128 * interval.as_nanos() * self.window / Duration::from_secs(1).as_nanos();
+        self.ramp = Some(Ramp { from: self.amount, to, steps: max(1, steps), step: 0 })
+    }
+
+    /// The maximum capacity credited on any tick.
+    fn maximum(&self) -> usize {
+        self.amount.div_ceil(self.window) as usize
+    }
+
+    /// The capacity to credit on the next tick.
+    fn next(&mut self) -> usize {
+        if let Some(r) = &mut self.ramp {
+            r.step += 1;
+            self.amount = if r.to < r.from {
+                r.from - (r.from - r.to) * r.step / r.steps
+            } else {
+                r.from + (r.to - r.from) * r.step / r.steps
+            };
+            if r.step == r.steps {
+                self.ramp = None
+            }
+        }
+        let total = self.amount + self.carry;
+        self.carry = total % self.window;
+        (total / self.window) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+
+    use futures::executor::{self, NotifyHandle};
+    use log::{info, Level, LevelFilter, Log, Metadata, Record};
+    use std::{
+        io,
+        mem,
+        str,
+        sync::OnceLock,
+        thread::{self, ThreadId}
+    };
+    use super::*;
+    use crate::testing::Wakeups;
+    use tokio::{
+        self,
+        io::{copy, read_exact},
+        net::{TcpListener, TcpStream},
+        prelude::*,
+        runtime::Runtime,
+        timer::Delay
+    };
+    use futures::future;
+
+    /// A logger which records the messages of this crate per thread and
+    /// forwards everything to `env_logger`.
+    struct Capture {
+        inner: env_logger::Logger,
+        records: Mutex<Vec<(ThreadId, Level, String)>>
+    }
+
+    impl Capture {
+        /// The messages logged by the current thread.
+        fn logs(&self) -> Vec<(Level, String)> {
+            let id = thread::current().id();
+            self.records.lock()
+                .iter()
+                .filter(|r| r.0 == id)
+                .map(|r| (r.1, r.2.clone()))
+                .collect()
+        }
+    }
+
+    impl Log for Capture {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, r: &Record) {
+            if r.target().starts_with("aio_limited") {
+                self.records.lock().push((thread::current().id(), r.level(), r.args().to_string()))
+            }
+            if self.inner.matches(r) {
+                self.inner.log(r)
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn init_logger() -> &'static Capture {
+        static LOGGER: OnceLock<&'static Capture> = OnceLock::new();
+        LOGGER.get_or_init(|| {
+            let inner = env_logger::Builder::from_default_env()
+                .filter(None, LevelFilter::Info)
+                .build();
+            let capture = Box::leak(Box::new(Capture { inner, records: Mutex::new(Vec::new()) }));
+            log::set_logger(capture).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+            capture
+        })
+    }
+
+    /// Enqueue a part with a task which reports `k` to `handle` when notified.
+    fn enqueue(lim: &Limiter, id: Id, handle: &NotifyHandle, k: usize) {
+        executor::spawn(()).poll_fn_notify(handle, k, |_| lim.enqueue(id)).unwrap()
+    }
+
+    fn echo_server(
+        addr: &str,
+        lr: Option<Limiter>,
+        lw: Limiter,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let srv = TcpListener::bind(&addr.parse().unwrap())
+            .unwrap()
+            .incoming()
+            .map_err(|e| error!("accept failed = {:?}", e))
+            .for_each(move |s| {
+                let (r, w) = s.split();
+                let reader = match lr {
+                    Some(ref lim) => {
+                        Box::new(Limited::new(r, lim.clone()).unwrap()) as Box<dyn AsyncRead + Send>
+                    }
+                    None => Box::new(r) as Box<dyn AsyncRead + Send>,
+                };
+                let writer = Limited::new(w, lw.clone()).unwrap();
+                let future = copy(reader, writer)
+                    .map(|_| ())
+                    .map_err(|e| error!("copy failed = {:?}", e));
+                tokio::spawn(future)
+            });
+        Box::new(srv)
+    }
+
+    fn echo_client(addr: &str) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let clt = TcpStream::connect(&addr.parse().unwrap())
+            .and_then(|stream| copy(&b"0123456789"[..], stream))
+            .and_then(|(n, _, stream)| read_exact(stream, vec![0; n as usize]))
+            .map(|(_stream, buf)| info!("{:?}", str::from_utf8(&buf)))
+            .map_err(|e| error!("client error: {:?}", e));
+        Box::new(clt)
+    }
+
+    fn echo_rate_client(addr: &str, size: usize) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let clt = TcpStream::connect(&addr.parse().unwrap())
+            .and_then(move |stream| {
+                Delay::new(Instant::now() + Duration::from_secs(1)).then(|_| Ok(stream))
+            })
             .and_then(move |stream| {
                 copy(io::repeat(1).take(size as u64), stream)
                     .and_then(move |(_, _, stream)| {
@@ -168,11 +1594,1313 @@ mod tests {
         Box::new(clt)
     }
 
+    #[test]
+    fn longest_waiting_tasks_are_served_first() {
+        let rate = 100;
+        let (lim, mut clock) = Limiter::with_manual_clock(rate);
+        let ids = (0 .. 5).map(|_| lim.register().unwrap()).collect::<Vec<_>>();
+        let wakeups = Arc::new(Wakeups::default());
+        let handle = NotifyHandle::from(wakeups.clone());
+        // all parts are waiting for capacity
+        for (k, id) in ids.iter().enumerate() {
+            enqueue(&lim, *id, &handle, k)
+        }
+        let mut served = vec![0; ids.len()];
+        for _ in 0 .. 100 {
+            clock.tick();
+            let ready = mem::take(&mut *wakeups.0.lock());
+            assert_eq!(ids.len(), ready.len());
+            for k in ready {
+                // every woken part greedily takes whatever it can get
+                while let Ok(t) = lim.acquire(ids[k], rate) {
+                    served[k] += t.consume()
+                }
+                enqueue(&lim, ids[k], &handle, k)
+            }
+        }
+        let min = served.iter().min().unwrap();
+        let max = served.iter().max().unwrap();
+        assert!(max - min <= rate, "served = {:?}", served)
+    }
+
+    #[test]
+    fn backoff_reduces_wakeups_of_starved_parts() {
+        fn wakeups(b: LimiterBuilder) -> usize {
+            let (lim, mut clock) = b.build_with_manual_clock();
+            let ids = (0 .. 10).map(|_| lim.register().unwrap()).collect::<Vec<_>>();
+            let wakeups = Arc::new(Wakeups::default());
+            let handle = NotifyHandle::from(wakeups.clone());
+            for (k, id) in ids.iter().enumerate() {
+                // paused parts never get any capacity
+                lim.set_paused(*id, true);
+                enqueue(&lim, *id, &handle, k)
+            }
+            let mut total = 0;
+            for _ in 0 .. 20 {
+                clock.tick();
+                let ready = mem::take(&mut *wakeups.0.lock());
+                total += ready.len();
+                for k in ready {
+                    assert!(lim.acquire(ids[k], 100).is_err());
+                    enqueue(&lim, ids[k], &handle, k)
+                }
+            }
+            total
+        }
+        assert_eq!(200, wakeups(Limiter::builder(100)));
+        assert!(wakeups(Limiter::builder(100).backoff(4)) <= 60)
+    }
+
+    #[test]
+    fn soft_start_ramps_up_new_parts() {
+        let (lim, mut clock) = Limiter::builder(100).soft_start(4).build_with_manual_clock();
+        let a = lim.register().unwrap();
+        assert_eq!(25, lim.drain_chunks(a).map(Token::consume).sum::<usize>());
+        let b = lim.register().unwrap();
+        assert_eq!(25, lim.drain_chunks(b).map(Token::consume).sum::<usize>());
+        clock.tick();
+        assert_eq!(50, lim.drain_chunks(a).map(Token::consume).sum::<usize>());
+        clock.tick();
+        clock.tick();
+        assert_eq!(100, lim.drain_chunks(a).map(Token::consume).sum::<usize>())
+    }
+
+    #[test]
+    fn driven_by_external_interval() {
+        let now = Instant::now();
+        let (lim, driver) = Limiter::from_interval(stream::iter_ok::<_, io::Error>(vec![now, now]), 100);
+        let id = lim.register().unwrap();
+        assert_eq!(100, lim.acquire(id, 1000).unwrap().consume());
+        assert!(lim.acquire(id, 1000).is_err());
+        assert!(driver.wait().is_ok());
+        assert_eq!(2, lim.tick());
+        assert_eq!(100, lim.acquire(id, 1000).unwrap().consume());
+        assert!(lim.is_healthy());
+
+        let items = vec![Ok(now), Err(io::Error::other("clock failure"))];
+        let (lim, driver) = Limiter::from_interval(stream::iter_result(items), 100);
+        assert!(driver.wait().is_err());
+        assert_eq!(1, lim.tick());
+        assert!(!lim.is_healthy())
+    }
+
+    #[test]
+    fn unspawned_timer() {
+        let mut rt = Runtime::new().unwrap();
+        let (lim, timer) = Limiter::builder(0).rate_per(10, Duration::from_millis(100)).build_unspawned();
+        rt.spawn(timer);
+        thread::sleep(Duration::from_millis(350));
+        assert!(lim.tick() >= 2);
+        assert!(lim.is_healthy());
+        rt.shutdown_now().wait().unwrap();
+
+        // without a running timer the future completes with an error
+        let (lim, timer) = Limiter::new_unspawned(100);
+        assert!(lim.is_healthy());
+        assert!(timer.wait().is_err());
+        assert!(!lim.is_healthy())
+    }
+
+    #[test]
+    fn rate_per_window() {
+        let b = Limiter::builder(0).rate_per(100, Duration::from_secs(10));
+        let interval = b.interval();
+        let (lim, mut clock) = b.build_with_manual_clock();
+        let id = lim.register().unwrap();
+        let mut elapsed = Duration::from_secs(0);
+        let mut total = 0;
+        loop {
+            clock.tick();
+            elapsed += interval;
+            while let Ok(t) = lim.acquire(id, 1000 - total) {
+                total += t.consume()
+            }
+            if total == 1000 {
+                break
+            }
+        }
+        assert_eq!(Duration::from_secs(100), elapsed)
+    }
+
+    #[test]
+    fn rate_is_ramped_down_gradually() {
+        let (lim, mut clock) = Limiter::with_manual_clock(1000);
+        let id = lim.register().unwrap();
+        lim.ramp_rate(100, Duration::from_secs(5));
+        // the ramp begins with the next time window
+        assert_eq!(1000, lim.rate());
+        let mut rates = Vec::new();
+        for _ in 0 .. 7 {
+            clock.tick();
+            let mut n = 0;
+            while let Ok(t) = lim.acquire(id, usize::MAX) {
+                n += t.consume()
+            }
+            assert_eq!(lim.rate(), n);
+            rates.push(n)
+        }
+        assert_eq!(vec![820, 640, 460, 280, 100, 100, 100], rates);
+
+        // a ramp in progress is replaced, starting from where it got to
+        lim.ramp_rate(1000, Duration::from_secs(2));
+        clock.tick();
+        lim.ramp_rate(0, Duration::from_secs(0));
+        assert_eq!(550, lim.rate());
+        clock.tick();
+        assert_eq!(0, lim.rate())
+    }
+
+    #[test]
+    fn limiters_are_compared_by_identity() {
+        use std::collections::{hash_map::DefaultHasher, HashMap};
+
+        fn hash(lim: &Limiter) -> u64 {
+            let mut h = DefaultHasher::new();
+            lim.hash(&mut h);
+            h.finish()
+        }
+
+        let (a, _clock_a) = Limiter::with_manual_clock(100);
+        let (b, _clock_b) = Limiter::with_manual_clock(100);
+        assert_eq!(a, a.clone());
+        assert_eq!(hash(&a), hash(&a.clone()));
+        assert_eq!(a, a.downgrade().upgrade().unwrap());
+        assert_ne!(a, b);
+        assert_ne!(a, a.clone_with_rate(100));
+        assert_ne!(a, a.child(100).unwrap());
+
+        // only the identity is hashed, not the mutable state
+        #[allow(clippy::mutable_key_type)]
+        let mut names = HashMap::new();
+        names.insert(a.clone(), "a");
+        names.insert(b.clone(), "b");
+        names.insert(a.clone(), "c");
+        assert_eq!(2, names.len());
+        assert_eq!(Some(&"c"), names.get(&a));
+        assert_eq!(Some(&"b"), names.get(&b))
+    }
+
+    #[test]
+    fn credit_carries_fractions() {
+        let mut credit = Credit::new(1, Duration::from_secs(3), Duration::from_secs(1));
+        assert_eq!(1, credit.maximum());
+        let credited = (0 .. 30).map(|_| credit.next()).collect::<Vec<_>>();
+        assert_eq!(10, credited.iter().sum::<usize>());
+        assert!(credited.chunks(3).all(|c| c == [0, 0, 1]))
+    }
+
+    #[test]
+    fn on_tick_is_called_every_second() {
+        let rt = Runtime::new().unwrap();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let t = ticks.clone();
+        let _lim = Limiter::builder(100)
+            .on_tick(move |_| {
+                t.fetch_add(1, Ordering::SeqCst);
+            })
+            .build(&mut rt.executor())
+            .unwrap();
+        thread::sleep(Duration::from_millis(2500));
+        let n = ticks.load(Ordering::SeqCst);
+        assert!((2 ..= 3).contains(&n), "ticks = {}", n);
+        rt.shutdown_now().wait().unwrap()
+    }
+
+    #[test]
+    fn probe_measures_capacity_without_taking_it() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        let _idle = lim.register().unwrap();
+        let mut probe = executor::spawn(lim.probe(id, Duration::from_millis(3500)));
+        let handle = NotifyHandle::from(Arc::new(Wakeups::default()));
+        assert!(probe.poll_future_notify(&handle, 0).unwrap().is_not_ready());
+        assert_eq!(100, lim.available());
+        clock.tick();
+        assert!(probe.poll_future_notify(&handle, 0).unwrap().is_not_ready());
+        clock.tick();
+        assert_eq!(Async::Ready(300), probe.poll_future_notify(&handle, 0).unwrap());
+        assert_eq!(100, lim.available());
+        assert_eq!(0, lim.total_transferred());
+        drop(probe);
+        assert!(lim.waiting().is_empty());
+
+        let rt = Runtime::new().unwrap();
+        let lim = Limiter::builder(0)
+            .rate_per(100, Duration::from_millis(100))
+            .build(&mut rt.executor())
+            .unwrap();
+        let id = lim.register().unwrap();
+        let total = lim.probe(id, Duration::from_millis(500)).wait().unwrap();
+        assert_eq!(500, total);
+        rt.shutdown_now().wait().unwrap()
+    }
+
+    #[test]
+    fn reservations_are_handed_out_across_windows() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        let mut r = executor::spawn(lim.reserve(id, 300));
+        let handle = NotifyHandle::from(Arc::new(Wakeups::default()));
+        let mut chunks = Vec::new();
+        loop {
+            match r.poll_stream_notify(&handle, 0).unwrap() {
+                Async::Ready(Some(t)) => chunks.push((lim.tick(), t.consume())),
+                Async::Ready(None) => break,
+                Async::NotReady => {
+                    assert_eq!(vec![id], lim.waiting());
+                    clock.tick();
+                }
+            }
+        }
+        assert_eq!(vec![(0, 100), (1, 100), (2, 100)], chunks);
+        assert_eq!(0, r.get_ref().remaining());
+        assert_eq!(300, lim.total_transferred())
+    }
+
+    #[test]
+    fn ticks_are_streamed() {
+        let mut rt = Runtime::new().unwrap();
+        let lim = Limiter::builder(0)
+            .rate_per(10, Duration::from_millis(100))
+            .build(&mut rt.executor())
+            .unwrap();
+        let ticks = rt.block_on(lim.ticks().take(5).collect()).unwrap();
+        assert_eq!(5, ticks.len());
+        assert!(ticks[0] >= 1);
+        assert!(ticks.windows(2).all(|w| w[0] < w[1]), "ticks = {:?}", ticks);
+        assert!(lim.waiting().is_empty());
+        rt.shutdown_now().wait().unwrap();
+
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let mut ticks = executor::spawn(lim.ticks());
+        let handle = NotifyHandle::from(Arc::new(Wakeups::default()));
+        assert!(ticks.poll_stream_notify(&handle, 0).unwrap().is_not_ready());
+        clock.tick();
+        clock.tick();
+        assert_eq!(Async::Ready(Some(2)), ticks.poll_stream_notify(&handle, 0).unwrap());
+        assert!(ticks.poll_stream_notify(&handle, 0).unwrap().is_not_ready());
+        clock.tick();
+        assert_eq!(Async::Ready(Some(3)), ticks.poll_stream_notify(&handle, 0).unwrap())
+    }
+
+    #[test]
+    fn threaded_timer_needs_no_runtime() {
+        let lim = Limiter::builder(0)
+            .rate_per(10, Duration::from_millis(50))
+            .build_threaded()
+            .unwrap();
+        let id = lim.register().unwrap();
+        let start = Instant::now();
+        let mut total = 0;
+        while total < 50 {
+            match lim.acquire(id, usize::MAX) {
+                Ok(t) => total += t.consume(),
+                Err(_) => thread::sleep(Duration::from_millis(5))
+            }
+        }
+        // 10 bytes right away and 10 on each of 4 more ticks
+        assert_eq!(50, total);
+        assert!(start.elapsed() >= Duration::from_millis(190), "elapsed = {:?}", start.elapsed());
+        assert!(lim.tick() >= 4);
+
+        // the thread exits once the limiter is gone
+        let weak = lim.downgrade();
+        drop(lim);
+        assert!(eventually(|| weak.upgrade().is_none()));
+
+        // ... right away, instead of on its next tick, which is a second away
+        let lim = Limiter::new_threaded(100).unwrap();
+        lim.notify_all();
+        thread::sleep(Duration::from_millis(20));
+        // being woken up is not a tick
+        assert_eq!(0, lim.tick());
+        let bucket = Arc::downgrade(&lim.bucket);
+        let start = Instant::now();
+        drop(lim);
+        assert!(eventually(|| bucket.strong_count() == 0));
+        assert!(start.elapsed() < Duration::from_millis(900), "elapsed = {:?}", start.elapsed())
+    }
+
+    /// Poll the condition until it holds, for up to five seconds.
+    fn eventually<F: Fn() -> bool>(f: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f() {
+            if Instant::now() >= deadline {
+                return false
+            }
+            thread::sleep(Duration::from_millis(1))
+        }
+        true
+    }
+
+    #[test]
+    fn tick_counts_elapsed_windows() {
+        let rt = Runtime::new().unwrap();
+        let lim = Limiter::new(&mut rt.executor(), 100).unwrap();
+        assert_eq!(0, lim.tick());
+        thread::sleep(Duration::from_millis(2500));
+        let n = lim.tick();
+        assert!((2 ..= 3).contains(&n), "ticks = {}", n);
+        rt.shutdown_now().wait().unwrap();
+
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        for i in 1 ..= 3 {
+            clock.tick();
+            assert_eq!(i, lim.tick())
+        }
+    }
+
+    #[test]
+    fn tick_info() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        let t = lim.acquire(id, 30).unwrap();
+        assert_eq!(30, t.get());
+        enqueue(&lim, id, &NotifyHandle::from(Arc::new(Wakeups::default())), 0);
+        assert_eq!(TickInfo { index: 1, served: 30, waiting: 1 }, clock.tick());
+        assert_eq!(TickInfo { index: 2, served: 0, waiting: 0 }, clock.tick())
+    }
+
+    #[test]
+    fn manual_clock() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let a = lim.register().unwrap();
+        let b = lim.register().unwrap();
+        for _ in 0 .. 3 {
+            assert_eq!(50, lim.acquire(a, 1000).unwrap().consume());
+            let mut t = lim.acquire(b, 1000).unwrap();
+            assert_eq!(50, t.get());
+            t.set(5);
+            lim.release(t);
+            assert_eq!(2, lim.acquire(b, 1000).unwrap().consume());
+            assert_eq!(1, lim.acquire(a, 1000).unwrap().consume());
+            assert_eq!(98, clock.tick().served)
+        }
+    }
+
+    #[test]
+    fn mock_clock_drives_refills() {
+        let time = crate::MockClock::new();
+        let (lim, mut clock) = Limiter::builder(100).clock(time.clone()).build_with_manual_clock();
+        let id = lim.register().unwrap();
+        assert_eq!(100, lim.acquire(id, 1000).unwrap().consume());
+        time.advance(Duration::from_millis(500));
+        assert_eq!(0, clock.catch_up());
+        assert!(lim.acquire(id, 1000).is_err());
+        time.advance(Duration::from_millis(600));
+        assert_eq!(1, clock.catch_up());
+        assert_eq!(100, lim.acquire(id, 1000).unwrap().consume());
+        // windows are counted from their start, not from the last refill
+        time.advance(Duration::from_millis(2900));
+        assert_eq!(3, clock.catch_up());
+        assert_eq!(4, lim.tick());
+        assert_eq!(100, lim.available());
+        lim.deregister(id)
+    }
+
+    #[test]
+    fn time_to_next_tick_decreases() {
+        let time = crate::MockClock::new();
+        let (lim, mut clock) = Limiter::builder(100).clock(time.clone()).build_with_manual_clock();
+        assert_eq!(Duration::from_secs(1), lim.time_to_next_tick());
+        time.advance(Duration::from_millis(300));
+        assert_eq!(Duration::from_millis(700), lim.time_to_next_tick());
+        time.advance(Duration::from_millis(650));
+        assert_eq!(Duration::from_millis(50), lim.time_to_next_tick());
+        time.advance(Duration::from_millis(200));
+        assert_eq!(Duration::from_secs(0), lim.time_to_next_tick());
+        // windows started by catching up keep their regular start
+        assert_eq!(1, clock.catch_up());
+        assert_eq!(Duration::from_millis(850), lim.time_to_next_tick());
+        clock.tick();
+        assert_eq!(Duration::from_secs(1), lim.time_to_next_tick());
+
+        let rt = Runtime::new().unwrap();
+        let lim = Limiter::builder(100)
+            .rate_per(10, Duration::from_millis(500))
+            .build(&mut rt.executor())
+            .unwrap();
+        let first = lim.time_to_next_tick();
+        assert!(first <= Duration::from_millis(500));
+        thread::sleep(Duration::from_millis(100));
+        assert!(lim.time_to_next_tick() < first);
+        rt.shutdown_now().wait().unwrap()
+    }
+
+    #[test]
+    fn continuous_refill_is_smooth() {
+        fn spacing(continuous: bool) -> Vec<usize> {
+            let time = crate::MockClock::new();
+            let (lim, mut clock) = Limiter::builder(100)
+                .continuous(continuous)
+                .clock(time.clone())
+                .build_with_manual_clock();
+            let id = lim.register().unwrap();
+            assert_eq!(100, lim.acquire(id, 1000).unwrap().consume());
+            (0 .. 20).map(|_| {
+                time.advance(Duration::from_millis(100));
+                clock.catch_up();
+                lim.acquire(id, 1000).map_or(0, Token::consume)
+            })
+            .collect()
+        }
+        let mut bursty = vec![0; 20];
+        bursty[9] = 100;
+        bursty[19] = 100;
+        assert_eq!(bursty, spacing(false));
+        assert_eq!(vec![10; 20], spacing(true))
+    }
+
+    #[test]
+    fn algorithm_is_swapped_mid_transfer() {
+        let time = crate::MockClock::new();
+        let (lim, mut clock) = Limiter::builder(100).clock(time.clone()).build_with_manual_clock();
+        let a = lim.register().unwrap();
+        let b = lim.register().unwrap();
+        let mut t = lim.acquire(a, 1000).unwrap();
+        assert_eq!(50, t.get());
+        lim.set_algorithm(Box::new(ContinuousRefill));
+        assert_eq!(50, lim.available());
+        // a token of the previous algorithm is given back as usual
+        t.set(20);
+        lim.release(t);
+        assert_eq!((70, 30), (lim.available(), lim.total_transferred()));
+        // capacity accrues from now on, up to the maximum
+        time.advance(Duration::from_millis(300));
+        assert_eq!(0, clock.catch_up());
+        assert_eq!(100, lim.available());
+        assert_eq!(100, lim.drain_chunks(b).map(Token::consume).sum::<usize>());
+        time.advance(Duration::from_millis(100));
+        let u = lim.acquire(a, 1000).unwrap();
+        assert_eq!(5, u.get());
+        time.advance(Duration::from_millis(600));
+        assert_eq!(1, clock.catch_up());
+        assert_eq!(65, lim.available());
+        // a token of a previous time window is ignored after the swap back
+        lim.set_algorithm(Box::new(TokenBucket));
+        drop(u);
+        assert_eq!((65, 135), (lim.available(), lim.total_transferred()));
+        time.advance(Duration::from_millis(1000));
+        assert_eq!(1, clock.catch_up());
+        assert_eq!(100, lim.available());
+        assert_eq!(2, lim.parts())
+    }
+
+    #[test]
+    fn derived_limiter_shares_the_clock() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let half = lim.clone_with_rate(50);
+        let a = lim.register().unwrap();
+        let b = half.register().unwrap();
+        for i in 1 ..= 3 {
+            assert_eq!(100, lim.acquire(a, 1000).unwrap().consume());
+            assert_eq!(50, half.acquire(b, 1000).unwrap().consume());
+            assert!(lim.acquire(a, 1000).is_err());
+            assert!(half.acquire(b, 1000).is_err());
+            clock.tick();
+            assert_eq!(i, lim.tick());
+            assert_eq!(i, half.tick())
+        }
+    }
+
+    #[test]
+    fn released_capacity_is_available_again() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        let mut t = lim.acquire(id, 1000).unwrap();
+        assert_eq!(100, t.get());
+        t.set(40); // 60 used
+        lim.release(t);
+        assert_eq!(40, lim.acquire(id, 1000).unwrap().consume());
+        assert!(lim.acquire(id, 1000).is_err());
+        // tokens of a previous time window are ignored
+        clock.tick();
+        let old = lim.acquire(id, 10).unwrap();
+        clock.tick();
+        lim.release(old);
+        assert_eq!(100, lim.acquire(id, 1000).unwrap().consume());
+        lim.deregister(id)
+    }
+
+    #[test]
+    fn throttles_are_counted() {
+        let (lim, mut clock) = Limiter::with_manual_clock(10);
+        let id = lim.register().unwrap();
+        let mut count = lim.throttle_count();
+        assert_eq!(0, count);
+        for _ in 0 .. 3 {
+            while lim.acquire(id, 3).map(Token::consume).is_ok() {}
+            assert!(lim.acquire(id, 3).is_err());
+            assert!(lim.throttle_count() >= count + 2);
+            count = lim.throttle_count();
+            clock.tick();
+        }
+        // successful requests do not count
+        lim.acquire(id, 3).unwrap().consume();
+        assert_eq!(count, lim.throttle_count());
+        lim.deregister(id)
+    }
+
+    #[test]
+    fn achieved_rate_follows_the_load() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let ids = (0 .. 2).map(|_| lim.register().unwrap()).collect::<Vec<_>>();
+        assert_eq!(0, lim.achieved_rate());
+        for _ in 0 .. 5 {
+            for id in &ids {
+                while lim.acquire(*id, 7).map(Token::consume).is_ok() {}
+            }
+            clock.tick();
+            let achieved = lim.achieved_rate();
+            assert!(achieved > 90 && achieved <= lim.rate(), "achieved = {}", achieved)
+        }
+        // an idle window achieves nothing
+        clock.tick();
+        assert_eq!(0, lim.achieved_rate());
+        for id in ids {
+            lim.deregister(id)
+        }
+    }
+
+    #[test]
+    fn time_to_send_depends_on_parts() {
+        let (lim, _clock) = Limiter::with_manual_clock(100);
+        assert_eq!(Duration::from_secs(2), lim.time_to_send(200));
+        let a = lim.register().unwrap();
+        assert_eq!(Duration::from_secs(2), lim.time_to_send(200));
+        let b = lim.register().unwrap();
+        assert_eq!(Duration::from_secs(4), lim.time_to_send(200));
+        assert_eq!(Duration::from_millis(500), lim.time_to_send(25));
+        lim.deregister(a);
+        lim.deregister(b)
+    }
+
+    #[test]
+    fn children_borrow_idle_capacity_of_their_parent() {
+        let (parent, mut clock) = Limiter::with_manual_clock(100);
+        let a = parent.child(80).unwrap();
+        let b = parent.child(80).unwrap();
+        assert_eq!(2, parent.parts());
+        let x = a.register().unwrap();
+        let y = b.register().unwrap();
+        // `b` is idle, so `a` gets more than half, up to its own maximum
+        assert_eq!(80, a.drain_chunks(x).map(Token::consume).sum::<usize>());
+        assert_eq!(20, parent.available());
+        clock.tick();
+        // both are busy and share the capacity of their parent
+        let mut served = [0, 0];
+        loop {
+            let s = served;
+            for (k, (lim, id)) in [(&a, x), (&b, y)].iter().enumerate() {
+                if let Ok(t) = lim.acquire(*id, 10) {
+                    served[k] += t.consume()
+                }
+            }
+            if s == served {
+                break
+            }
+        }
+        assert_eq!(100, served[0] + served[1]);
+        assert!(served.iter().all(|&n| n >= 40), "served = {:?}", served);
+        clock.tick();
+        // capacity given back to a child is given back to the parent
+        let t = a.acquire(x, 30).unwrap();
+        assert_eq!(70, parent.available());
+        a.release(t);
+        assert_eq!(100, parent.available());
+        a.deregister(x);
+        b.deregister(y);
+        drop((a, b));
+        assert_eq!(0, parent.parts())
+    }
+
+    #[test]
+    fn parts_are_registered_in_bulk() {
+        let (lim, _clock) = Limiter::builder(1000).max_parts(150).build_with_manual_clock();
+        let ids = lim.register_many(100).unwrap();
+        assert_eq!(100, lim.parts());
+        assert_eq!(100, ids.iter().collect::<std::collections::HashSet<_>>().len());
+        // either all or none are registered
+        assert!(matches!(lim.register_many(100), Err(Error::TooManyParts)));
+        assert_eq!(100, lim.parts());
+        let resources = ids.into_iter().map(|id| Limited::from_id(io::sink(), lim.clone(), id)).collect::<Vec<_>>();
+        assert_eq!(10, lim.acquire(resources[0].id(), 1000).unwrap().consume());
+        drop(resources);
+        assert_eq!(0, lim.parts())
+    }
+
+    #[test]
+    fn waiting_parts_are_listed() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let ids = (0 .. 4).map(|_| lim.register().unwrap()).collect::<Vec<_>>();
+        let handle = NotifyHandle::from(Arc::new(Wakeups::default()));
+        assert!(lim.waiting().is_empty());
+        enqueue(&lim, ids[2], &handle, 2);
+        enqueue(&lim, ids[0], &handle, 0);
+        enqueue(&lim, ids[3], &handle, 3);
+        assert_eq!(vec![ids[2], ids[0], ids[3]], lim.waiting());
+        clock.tick();
+        assert!(lim.waiting().is_empty());
+        for id in ids {
+            lim.deregister(id)
+        }
+    }
+
+    #[test]
+    fn idle_parts_decay() {
+        let (lim, mut clock) = Limiter::builder(300).idle_decay(0.5, 0.1).build_with_manual_clock();
+        let (a, b, c) = (lim.register().unwrap(), lim.register().unwrap(), lim.register().unwrap());
+        let mut shares = Vec::new();
+        for k in 0 .. 8 {
+            if k == 5 {
+                // `c` becomes active again
+                lim.acquire(c, 1).unwrap().consume();
+            }
+            shares.push(lim.acquire(a, 1000).unwrap().consume());
+            lim.acquire(b, 1000).unwrap().consume();
+            clock.tick();
+        }
+        assert_eq!(vec![100, 120, 133, 141, 143, 100, 100, 120], shares);
+        for id in [a, b, c] {
+            lim.deregister(id)
+        }
+    }
+
+    #[test]
+    fn availability_is_awaited() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        while lim.acquire(id, 1000).map(Token::consume).is_ok() {}
+        let wakeups = Arc::new(Wakeups::default());
+        let handle = NotifyHandle::from(wakeups.clone());
+        let mut available = executor::spawn(lim.block_until_available(50));
+        assert!(available.poll_future_notify(&handle, 7).unwrap().is_not_ready());
+        assert_eq!(1, lim.parts());
+        clock.tick();
+        assert_eq!(vec![7], *wakeups.0.lock());
+        assert!(available.poll_future_notify(&handle, 7).unwrap().is_ready());
+        drop(available);
+        assert!(lim.waiting().is_empty());
+        lim.deregister(id)
+    }
+
+    #[test]
+    fn debug_format_does_not_block() {
+        let (lim, _clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        let unlocked = format!("{:?}", lim);
+        assert!(unlocked.contains("parts"));
+        assert!(!unlocked.contains("<locked>"));
+        let locked = lim.bucket.with_lock_held(|| format!("{:?}", lim));
+        assert!(locked.contains("capacity: <locked>"));
+        lim.deregister(id)
+    }
+
+    #[test]
+    fn rates_are_given_in_units() {
+        use crate::builder::Rate;
+        assert_eq!(1024, Rate::kib(1).as_bytes());
+        assert_eq!(1024 * 1024, Rate::mib(1).as_bytes());
+        assert_eq!(Rate::bytes(2048), Rate::kib(2));
+        assert_eq!(usize::MAX, Rate::mib(usize::MAX).as_bytes());
+        let (lim, mut clock) = Limiter::builder(0).rate(Rate::kib(1)).build_with_manual_clock();
+        assert_eq!(1024, lim.rate());
+        let id = lim.register().unwrap();
+        for _ in 0 .. 3 {
+            assert_eq!(1024, lim.acquire(id, 4096).unwrap().consume());
+            assert!(lim.acquire(id, 1).is_err());
+            clock.tick();
+        }
+        lim.deregister(id)
+    }
+
+    #[test]
+    fn limiter_can_be_paused() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        let wakeups = Arc::new(Wakeups::default());
+        let handle = NotifyHandle::from(wakeups.clone());
+        assert_eq!(30, lim.acquire(id, 30).unwrap().consume());
+        lim.pause();
+        assert!(lim.is_paused());
+        assert_eq!(0, lim.available());
+        for _ in 0 .. 3 {
+            assert_eq!(Some(0), lim.peek(id).ok());
+            assert!(lim.acquire(id, 30).is_err());
+            clock.tick();
+        }
+        enqueue(&lim, id, &handle, 1);
+        lim.resume();
+        assert!(!lim.is_paused());
+        assert_eq!(vec![1], *wakeups.0.lock());
+        assert!(lim.acquire(id, 30).is_err());
+        clock.tick();
+        assert_eq!(100, lim.acquire(id, 1000).unwrap().consume());
+        lim.deregister(id)
+    }
+
+    #[test]
+    fn stats_can_be_reset() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        assert_eq!(30, lim.acquire(id, 30).unwrap().consume());
+        while lim.acquire(id, 1000).map(Token::consume).is_ok() {}
+        assert_eq!(100, lim.total_transferred());
+        assert_eq!(1, lim.throttle_count());
+        lim.reset_stats();
+        assert_eq!(0, lim.total_transferred());
+        assert_eq!(0, lim.throttle_count());
+        #[cfg(feature = "wait-stats")]
+        assert_eq!(0, lim.wait_stats().count);
+        clock.tick();
+        assert_eq!(20, lim.acquire(id, 20).unwrap().consume());
+        assert_eq!(20, lim.total_transferred());
+        assert_eq!(0, lim.throttle_count());
+        lim.deregister(id)
+    }
+
+    #[test]
+    fn borrowed_resources_are_rate_limited() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(10);
+            let mut cursor = io::Cursor::new(vec![1; 100]);
+            {
+                let mut r = lim.wrap_mut(&mut cursor).unwrap();
+                let mut buf = [0; 100];
+                for _ in 0 .. 3 {
+                    assert_eq!(10, r.read(&mut buf).unwrap());
+                    assert!(r.read(&mut buf).is_err());
+                    clock.tick();
+                }
+                assert_eq!(1, lim.parts())
+            }
+            assert_eq!(0, lim.parts());
+            assert_eq!(30, cursor.position());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn transfers_are_paced_by_the_rate() {
+        let mut rt = Runtime::new().unwrap();
+        let lim = Limiter::builder(0)
+            .rate_per(100, Duration::from_millis(100))
+            .build(&mut rt.executor())
+            .unwrap();
+        let data = io::Cursor::new(vec![7; 500]);
+        let start = Instant::now();
+        let n = rt.block_on(lim.transfer(data, io::Cursor::new(Vec::new()))).unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(500, n);
+        // the first 100 bytes are copied right away, the rest over 4 windows
+        assert!(elapsed >= Duration::from_millis(350), "elapsed = {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "elapsed = {:?}", elapsed);
+        assert_eq!(0, lim.parts());
+        rt.shutdown_now().wait().unwrap()
+    }
+
+    #[test]
+    fn share_for_matches_acquire() {
+        let (lim, mut clock) = Limiter::with_manual_clock(101);
+        let ids = lim.register_many(3).unwrap();
+        lim.set_weight(ids[1], 3);
+        lim.set_weight(ids[2], 0);
+        assert_eq!(0, lim.share_for(ids[2]));
+        for _ in 0 .. 3 {
+            let mut granted = Vec::new();
+            for &id in ids.iter().chain(&ids) {
+                let share = lim.share_for(id);
+                let got = lim.acquire(id, usize::MAX).map_or(0, Token::consume);
+                assert_eq!(share, got);
+                granted.push(got)
+            }
+            assert_eq!(101, granted.iter().sum::<usize>());
+            assert!(granted[1] >= 75, "granted = {:?}", granted);
+            clock.tick();
+        }
+        for id in ids {
+            lim.deregister(id)
+        }
+    }
+
+    #[test]
+    fn max_parts_can_be_changed() {
+        let (lim, _clock) = Limiter::builder(100).max_parts(3).build_with_manual_clock();
+        let ids = (0 .. 3).map(|_| lim.register().unwrap()).collect::<Vec<_>>();
+        assert!(matches!(lim.register(), Err(Error::TooManyParts)));
+        lim.set_max_parts(1);
+        assert!(matches!(lim.register(), Err(Error::TooManyParts)));
+        // existing parts keep working
+        for id in &ids {
+            assert!(lim.acquire(*id, 10).map(Token::consume).is_ok())
+        }
+        lim.deregister(ids[0]);
+        lim.deregister(ids[1]);
+        assert!(matches!(lim.register(), Err(Error::TooManyParts)));
+        lim.deregister(ids[2]);
+        let id = lim.register().unwrap();
+        lim.set_max_parts(2);
+        lim.register().unwrap();
+        lim.deregister(id)
+    }
+
+    #[test]
+    fn dropped_tokens_give_back_their_capacity() {
+        let (lim, clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        let t = lim.acquire(id, 1000).unwrap();
+        assert_eq!(0, lim.available());
+        drop(t);
+        assert_eq!(100, lim.available());
+        // consumed capacity is not given back
+        assert_eq!(60, lim.acquire(id, 60).unwrap().consume());
+        assert_eq!(40, lim.available());
+        // tokens may outlive their limiter
+        let t = lim.acquire(id, 1000).unwrap();
+        drop((lim, clock));
+        assert_eq!(40, t.get())
+    }
+
+    #[test]
+    fn snapshot_agrees_with_getters() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let a = lim.register().unwrap();
+        let _b = lim.register().unwrap();
+        lim.acquire(a, 30).unwrap().consume();
+        clock.tick();
+        let mut t = lim.acquire(a, 1000).unwrap();
+        t.set(10);
+        lim.release(t);
+        let s = lim.snapshot();
+        assert_eq!(LimiterSnapshot {
+            rate: 100,
+            available: 60,
+            parts: 2,
+            transferred: 70,
+            tick: 1,
+            healthy: true
+        }, s);
+        assert_eq!(s.rate, lim.rate());
+        assert_eq!(s.available, lim.available());
+        assert_eq!(s.parts, lim.parts());
+        assert_eq!(s.transferred, lim.total_transferred());
+        assert_eq!(s.tick, lim.tick());
+        assert_eq!(s.healthy, lim.is_healthy())
+    }
+
+    #[test]
+    fn drain_chunks_yields_the_fair_share() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let a = lim.register().unwrap();
+        let b = lim.register().unwrap();
+        assert_eq!(50, lim.acquire(a, 1000).unwrap().consume());
+        assert_eq!(50, lim.drain_chunks(b).map(Token::consume).sum::<usize>());
+        assert_eq!(0, lim.drain_chunks(a).count());
+        clock.tick();
+        assert_eq!(100, lim.drain_chunks(a).map(Token::consume).sum::<usize>())
+    }
+
+    #[test]
+    fn weak_limiter_does_not_keep_the_limiter_alive() {
+        let (lim, clock) = Limiter::with_manual_clock(100);
+        let weak = lim.downgrade();
+        assert_eq!(100, weak.upgrade().unwrap().rate());
+        drop(lim);
+        // the clock still holds a handle
+        assert!(weak.upgrade().is_some());
+        drop(clock);
+        assert!(weak.upgrade().is_none())
+    }
+
+    /// The average latency of an enqueue on each of four threads, while
+    /// another thread keeps draining the tasks, together with the indices
+    /// of the threads whose tasks have been drained.
+    fn enqueue_latency<E, D>(enqueue: E, mut drain: D) -> (Vec<Duration>, Vec<usize>)
+    where
+        E: Fn(usize) + Sync,
+        D: FnMut() -> Vec<usize>
+    {
+        thread::scope(|s| {
+            let threads = (0 .. 4).map(|k| {
+                let enqueue = &enqueue;
+                s.spawn(move || {
+                    let start = Instant::now();
+                    for _ in 0 .. 10_000 {
+                        enqueue(k)
+                    }
+                    start.elapsed() / 10_000
+                })
+            })
+            .collect::<Vec<_>>();
+            let mut drained = Vec::new();
+            while threads.iter().any(|t| !t.is_finished()) {
+                drained.extend(drain())
+            }
+            drained.extend(drain());
+            drained.sort();
+            drained.dedup();
+            (threads.into_iter().map(|t| t.join().unwrap()).collect(), drained)
+        })
+    }
+
+    #[test]
+    fn enqueue_under_contention() {
+        init_logger();
+        let handle = NotifyHandle::from(Arc::new(Wakeups::default()));
+        let tasks = (0 .. 4)
+            .map(|k| executor::spawn(()).poll_fn_notify(&handle, k, |_| task::current()))
+            .collect::<Vec<_>>();
+        let lim = Limiter::unlimited();
+        let ids = (0 .. 4).map(|_| lim.register().unwrap()).collect::<Vec<_>>();
+        let index = |id: Id| ids.iter().position(|&x| x == id).unwrap();
+
+        // the backend of this build
+        let shared = enqueue_latency(
+            |k| lim.tasks.insert(ids[k], 0, Waiter::Task(tasks[k].clone())),
+            || lim.tasks.drain(false).0.into_iter().map(|(id, _)| index(id)).collect()
+        );
+
+        // a plain mutex, which is what the default backend amounts to
+        let set = Mutex::new(Tasks::with_backoff(0));
+        let mutex = enqueue_latency(
+            #[allow(clippy::unit_arg)] // `Stamp` is `()` without the `wait-stats` feature
+            |k| set.lock().insert(ids[k], 0, Waiter::Task(tasks[k].clone()), crate::tasks::stamp()),
+            || set.lock().drain(false).into_iter().map(|(id, _)| index(id)).collect()
+        );
+
+        let backend = if cfg!(feature = "lock-free") { "lock-free" } else { "mutex" };
+        info!("enqueue latency: {} {:?}, plain mutex {:?}", backend, shared.0, mutex.0);
+        for (latencies, drained) in [shared, mutex] {
+            assert_eq!(vec![0, 1, 2, 3], drained);
+            // a generous bound, as the machine may be loaded
+            assert!(latencies.iter().all(|&d| d < Duration::from_millis(1)), "latencies = {:?}", latencies)
+        }
+    }
+
+    #[test]
+    fn notify_all_wakes_waiting_tasks() {
+        let (lim, _clock) = Limiter::builder(100).backoff(4).build_with_manual_clock();
+        let wakeups = Arc::new(Wakeups::default());
+        let handle = NotifyHandle::from(wakeups.clone());
+        for k in 0 .. 3 {
+            enqueue(&lim, lim.register().unwrap(), &handle, k)
+        }
+        lim.notify_all();
+        assert_eq!(vec![0, 1, 2], *wakeups.0.lock());
+        lim.notify_all();
+        assert_eq!(3, wakeups.0.lock().len());
+    }
+
+    #[test]
+    fn lock_is_handed_over_fairly_only_while_tasks_wait() {
+        let (lim, mut clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        assert_eq!(10, lim.acquire(id, 10).unwrap().consume());
+        assert!(!lim.bucket.is_fair());
+        let handle = NotifyHandle::from(Arc::new(Wakeups::default()));
+        enqueue(&lim, id, &handle, 0);
+        assert!(lim.bucket.is_fair());
+        // all waiting tasks are notified on the tick
+        clock.tick();
+        assert!(!lim.bucket.is_fair());
+        enqueue(&lim, id, &handle, 0);
+        lim.notify_all();
+        assert!(!lim.bucket.is_fair())
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn waits_are_traced() {
+        use tracing::{field::{Field, Visit}, span, Event, Metadata, Subscriber};
+
+        /// Records spans and events as text, in order.
+        #[derive(Clone, Default)]
+        struct Recorder {
+            log: Arc<Mutex<Vec<String>>>,
+            spans: Arc<Mutex<Vec<String>>>, // the fields of all spans, by ID
+            current: Arc<Mutex<Vec<u64>>>, // the stack of entered spans
+        }
+
+        /// Formats the fields of a span or event.
+        #[derive(Default)]
+        struct Fields(Vec<String>);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0.push(format!("{}={:?}", field.name(), value))
+            }
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &span::Attributes) -> span::Id {
+                let mut fields = Fields::default();
+                span.record(&mut fields);
+                let name = format!("{} {}", span.metadata().name(), fields.0.join(" "));
+                self.log.lock().push(format!("new {}", name));
+                let mut spans = self.spans.lock();
+                spans.push(name);
+                span::Id::from_u64(spans.len() as u64)
+            }
+
+            fn record(&self, _: &span::Id, _: &span::Record) {}
+
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+            fn event(&self, event: &Event) {
+                let mut fields = Fields::default();
+                event.record(&mut fields);
+                let scope = match self.current.lock().last() {
+                    Some(&i) => format!(" in {}", self.spans.lock()[i as usize - 1]),
+                    None => String::new()
+                };
+                self.log.lock().push(format!("{}{}", fields.0.join(" "), scope))
+            }
+
+            fn enter(&self, span: &span::Id) {
+                self.current.lock().push(span.into_u64())
+            }
+
+            fn exit(&self, _: &span::Id) {
+                self.current.lock().pop();
+            }
+
+            fn try_close(&self, span: span::Id) -> bool {
+                let name = self.spans.lock()[span.into_u64() as usize - 1].clone();
+                self.log.lock().push(format!("close {}", name));
+                true
+            }
+        }
+
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let id = lim.register().unwrap();
+            let handle = NotifyHandle::from(Arc::new(Wakeups::default()));
+            while lim.acquire(id, 1000).map(Token::consume).is_ok() {}
+            enqueue(&lim, id, &handle, 0);
+            clock.tick();
+        });
+        let expected = [
+            "message=no capacity left part=1",
+            "new wait part=1",
+            "message=waiting for capacity in wait part=1",
+            "message=notified in wait part=1",
+            "close wait part=1",
+        ];
+        assert_eq!(&expected[..], &recorder.log.lock()[..]);
+    }
+
+    #[cfg(feature = "wait-stats")]
+    #[test]
+    fn wait_stats_under_saturation() {
+        let period = Duration::from_millis(20);
+        let (lim, mut clock) = Limiter::with_manual_clock(10);
+        let ids = (0 .. 5).map(|_| lim.register().unwrap()).collect::<Vec<_>>();
+        let wakeups = Arc::new(Wakeups::default());
+        let handle = NotifyHandle::from(wakeups.clone());
+        for _ in 0 .. 5 {
+            for (k, id) in ids.iter().enumerate() {
+                while lim.acquire(*id, 100).map(Token::consume).is_ok() {}
+                enqueue(&lim, *id, &handle, k)
+            }
+            thread::sleep(period);
+            clock.tick();
+        }
+        let stats = lim.wait_stats();
+        assert_eq!(25, stats.count);
+        assert!(stats.p50 >= period && stats.p50 < 10 * period, "{:?}", stats);
+        assert!(stats.p50 <= stats.p99 && stats.p99 <= stats.max, "{:?}", stats)
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn config_and_snapshot_as_json() {
+        let config = Limiter::builder(0)
+            .rate_per(100, Duration::from_secs(2))
+            .max_parts(1)
+            .backoff(3)
+            .config();
+        let json = serde_json::to_string(&config).unwrap();
+        let decoded = serde_json::from_str::<crate::LimiterConfig>(&json).unwrap();
+        assert_eq!(config, decoded);
+        let (lim, _clock) = LimiterBuilder::from_config(&decoded).build_with_manual_clock();
+        assert_eq!(50, lim.rate());
+        let _id = lim.register().unwrap();
+        assert!(lim.register().is_err());
+        let snapshot = lim.snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(snapshot, serde_json::from_str(&json).unwrap())
+    }
+
+    #[test]
+    fn tags_appear_in_logs() {
+        let logger = init_logger();
+        let (lim, _clock) = Limiter::with_manual_clock(10);
+        let id = lim.register().unwrap();
+        lim.set_tag(id, 42);
+        while lim.acquire(id, 100).map(Token::consume).is_ok() {}
+        assert_eq!(vec![(Level::Trace, format!("{} (tag 42): no capacity left", id))], logger.logs())
+    }
+
+    #[test]
+    fn throttling_is_logged() {
+        let logger = init_logger();
+        let (lim, mut clock) = Limiter::with_manual_clock(10);
+        let id = lim.register().unwrap();
+        assert_eq!(10, lim.acquire(id, 100).unwrap().consume());
+        assert!(lim.acquire(id, 100).is_err());
+        enqueue(&lim, id, &NotifyHandle::from(Arc::new(Wakeups::default())), 0);
+        clock.tick();
+        assert_eq!(vec![
+            (Level::Trace, format!("{}: no capacity left", id)),
+            (Level::Trace, format!("{}: waiting for capacity", id)),
+            (Level::Debug, format!("{}: notified", id))
+        ], logger.logs())
+    }
+
+    /// An in-memory duplex resource.
+    struct Duplex(io::Repeat, io::Sink);
+
+    impl io::Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl io::Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.1.flush()
+        }
+    }
+
+    impl AsyncRead for Duplex {}
+
+    impl AsyncWrite for Duplex {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn split_duplex() {
+        future::lazy(|| {
+            let (rlim, _rclock) = Limiter::with_manual_clock(10);
+            let (wlim, _wclock) = Limiter::with_manual_clock(5);
+            let io = Duplex(io::repeat(1), io::sink());
+            let (mut r, mut w) = Limiter::split_limited(io, &rlim, &wlim).unwrap();
+            let mut buf = [0; 100];
+            assert_eq!(10, r.read(&mut buf).unwrap());
+            assert_eq!(io::ErrorKind::WouldBlock, r.read(&mut buf).unwrap_err().kind());
+            assert_eq!(5, w.write(&buf).unwrap());
+            assert_eq!(io::ErrorKind::WouldBlock, w.write(&buf).unwrap_err().kind());
+            assert_eq!((1, 1), (rlim.parts(), wlim.parts()));
+
+            // a limiter with a maximum of 1 part can not have more parts
+            let (full, _clock) = Limiter::builder(1).max_parts(1).build_with_manual_clock();
+            let _part = Limited::new(io::sink(), full.clone()).unwrap();
+            let io = Duplex(io::repeat(1), io::sink());
+            assert!(Limiter::split_limited(io, &rlim, &full).is_err());
+            assert_eq!((1, 1), (rlim.parts(), full.parts()));
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn duplex_weighted_split() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let io = Duplex(io::repeat(1), io::sink());
+            let (mut r, mut w) = lim.duplex_weighted(io, 3, 7).unwrap();
+            let mut buf = [0; 1000];
+            for _ in 0 .. 3 {
+                let mut read = 0;
+                let mut written = 0;
+                // both directions are saturated in turns
+                loop {
+                    let n = r.read(&mut buf).unwrap_or(0);
+                    let m = w.write(&buf).unwrap_or(0);
+                    if n + m == 0 {
+                        break
+                    }
+                    read += n;
+                    written += m
+                }
+                assert_eq!((30, 70), (read, written));
+                clock.tick();
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn health_reflects_timer_error() {
+        let (lim, _clock) = Limiter::with_manual_clock(100);
+        assert!(lim.is_healthy());
+        lim.error.store(true, Ordering::Release);
+        assert!(!lim.is_healthy());
+        assert!(matches!(lim.register(), Err(Error::TimerError)))
+    }
+
+    #[test]
+    fn executor_shutdown_is_reported() {
+        let rt = Runtime::new().unwrap();
+        let lim = Limiter::new(&mut rt.executor(), 100).unwrap();
+        let mut w = Limited::new(io::sink(), lim.clone()).unwrap();
+        assert_eq!(100, w.write(&[0; 200]).unwrap());
+        rt.shutdown_now().wait().unwrap();
+        assert!(!lim.is_healthy());
+        let e = w.write(&[0; 200]).unwrap_err();
+        let inner = e.get_ref().and_then(|e| e.downcast_ref::<Error>());
+        assert!(matches!(inner, Some(Error::LimiterShutdown)), "error = {:?}", e);
+        // no new resources are registered with a limiter which is known to be gone
+        assert!(matches!(Limited::new(io::sink(), lim.clone()), Err(Error::LimiterShutdown)));
+
+        // a failure of the timer is reported as such
+        let (lim, timer) = Limiter::from_interval(stream::once(Err("broken")), 100);
+        assert!(timer.wait().is_err());
+        assert!(matches!(lim.register(), Err(Error::TimerError)))
+    }
+
+    #[test]
+    fn timer_failure_policy() {
+        let (lim, _clock) = Limiter::with_manual_clock(100);
+        let id = lim.register().unwrap();
+        assert_eq!(100, lim.acquire(id, 1000).unwrap().consume());
+        lim.error.store(true, Ordering::Release);
+        assert!(matches!(lim.acquire(id, 1000), Err(Error::TimerError)));
+
+        let (lim, _clock) = Limiter::builder(100)
+            .on_timer_failure(TimerFailure::FailOpen)
+            .build_with_manual_clock();
+        let id = lim.register().unwrap();
+        assert_eq!(100, lim.acquire(id, 1000).unwrap().consume());
+        assert!(lim.acquire(id, 1000).is_err());
+        lim.error.store(true, Ordering::Release);
+        assert!(!lim.is_healthy());
+        // the full capacity is granted over and over again
+        for _ in 0 .. 3 {
+            assert_eq!(100, lim.acquire(id, 1000).unwrap().consume())
+        }
+        assert!(lim.register().is_ok())
+    }
+
     #[test]
     fn test1() {
-        let _ = env_logger::Builder::from_default_env()
-            .filter(Some("aio_limited"), LevelFilter::Trace)
-            .try_init();
+        init_logger();
 
         let mut rt = Runtime::new().unwrap();
         let mut ex = rt.executor();
@@ -202,9 +2930,7 @@ mod tests {
 
     #[test]
     fn test2() {
-        let _ = env_logger::Builder::from_default_env()
-            .filter(None, LevelFilter::Info)
-            .try_init();
+        init_logger();
 
         let mut rt = Runtime::new().unwrap();
         let mut ex = rt.executor();
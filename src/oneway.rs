@@ -0,0 +1,193 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{algorithms::Id, error::{Error, Result}, limiter::Limiter};
+use futures::prelude::*;
+use std::{cmp::min, io};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+/// A resource which is only rate-limited when read from.
+///
+/// Unlike `Limited`, which implements whichever of reading and writing the
+/// wrapped resource supports, a `LimitedReader` only implements reading.
+#[derive(Debug)]
+pub struct LimitedReader<R> {
+    id: Id,
+    io: R,
+    lim: Limiter,
+}
+
+/// A resource which is only rate-limited when written to.
+///
+/// Unlike `Limited`, which implements whichever of reading and writing the
+/// wrapped resource supports, a `LimitedWriter` only implements writing.
+#[derive(Debug)]
+pub struct LimitedWriter<W> {
+    id: Id,
+    io: W,
+    lim: Limiter,
+}
+
+impl<R: AsyncRead> LimitedReader<R> {
+    /// Create a new rate-limited reader, registered as a new part of the
+    /// given `Limiter`.
+    pub fn new(io: R, lim: Limiter) -> Result<LimitedReader<R>> {
+        let id = lim.register()?;
+        Ok(LimitedReader { id, io, lim })
+    }
+}
+
+impl<W: AsyncWrite> LimitedWriter<W> {
+    /// Create a new rate-limited writer, registered as a new part of the
+    /// given `Limiter`.
+    pub fn new(io: W, lim: Limiter) -> Result<LimitedWriter<W>> {
+        let id = lim.register()?;
+        Ok(LimitedWriter { id, io, lim })
+    }
+}
+
+impl<R> Drop for LimitedReader<R> {
+    fn drop(&mut self) {
+        self.lim.deregister(self.id)
+    }
+}
+
+impl<W> Drop for LimitedWriter<W> {
+    fn drop(&mut self) {
+        self.lim.deregister(self.id)
+    }
+}
+
+/// Acquire capacity for up to `len` bytes and let `f` transfer at most the
+/// quantity granted. Capacity which `f` did not use is given back. Results
+/// larger than the quantity granted are clamped to it.
+fn transfer<F>(lim: &Limiter, id: Id, len: usize, f: F) -> io::Result<usize>
+where
+    F: FnOnce(usize) -> io::Result<usize>
+{
+    if len == 0 {
+        return f(0)
+    }
+    let mut t = match lim.acquire(id, len) {
+        Ok(t) => t,
+        Err(Error::NoCapacity) => {
+            lim.enqueue(id)?;
+            return Err(lim.throttled(id))
+        }
+        Err(Error::Closed) => return Ok(0),
+        Err(e) => return Err(e.into())
+    };
+    let n = t.get();
+    let result = f(n).map(|m| min(m, n));
+    t.set(n - *result.as_ref().unwrap_or(&0));
+    lim.release(t);
+    result
+}
+
+impl<R: AsyncRead> io::Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let io = &mut self.io;
+        transfer(&self.lim, self.id, buf.len(), |k| io.read(&mut buf[0..k]))
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for LimitedReader<R> {}
+
+impl<W: AsyncWrite> io::Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let io = &mut self.io;
+        transfer(&self.lim, self.id, buf.len(), |k| io.write(&buf[0..k]))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for LimitedWriter<W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use std::io::{Read, Write};
+    use super::*;
+
+    /// A resource which can only be read from.
+    struct ReadOnly;
+
+    impl Read for ReadOnly {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+    }
+
+    impl AsyncRead for ReadOnly {}
+
+    /// A resource which can only be written to.
+    struct WriteOnly(usize);
+
+    impl Write for WriteOnly {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for WriteOnly {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn reader_is_rate_limited() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut r = LimitedReader::new(ReadOnly, lim.clone()).unwrap();
+            for _ in 0 .. 3 {
+                assert_eq!(60, r.read(&mut [0; 60]).unwrap());
+                assert_eq!(40, r.read(&mut [0; 60]).unwrap());
+                let e = r.read(&mut [0; 60]).unwrap_err();
+                assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+                clock.tick();
+            }
+            assert_eq!(1, lim.parts());
+            drop(r);
+            assert_eq!(0, lim.parts());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn writer_is_rate_limited() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(100);
+            let mut w = LimitedWriter::new(WriteOnly(0), lim.clone()).unwrap();
+            for _ in 0 .. 3 {
+                while w.write(&[0; 30]).is_ok() {}
+                clock.tick();
+            }
+            assert_eq!(300, w.io.0);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+}
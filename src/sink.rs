@@ -0,0 +1,110 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{algorithms::Id, error::{Error, Result}, limiter::Limiter};
+use futures::prelude::*;
+use std::io;
+
+/// A rate-limited `Sink`.
+///
+/// Every item has a cost, as determined by the given cost function, which
+/// needs to be covered by capacity of the `Limiter` before the item is
+/// passed on to the inner sink. Capacity is accumulated across time windows,
+/// so items which cost more than a single window's share still make progress.
+#[derive(Debug)]
+pub struct LimitedSink<S, F> {
+    id: Id,
+    sink: S,
+    lim: Limiter,
+    cost: F,
+    credit: usize, // capacity acquired but not yet spent
+}
+
+impl<S, F> LimitedSink<S, F>
+where
+    S: Sink,
+    F: Fn(&S::SinkItem) -> usize
+{
+    pub fn new(sink: S, lim: Limiter, cost: F) -> Result<LimitedSink<S, F>> {
+        let id = lim.register()?;
+        Ok(LimitedSink { id, sink, lim, cost, credit: 0 })
+    }
+}
+
+impl<S, F> Drop for LimitedSink<S, F> {
+    fn drop(&mut self) {
+        self.lim.deregister(self.id)
+    }
+}
+
+impl<S, F> Sink for LimitedSink<S, F>
+where
+    S: Sink,
+    S::SinkError: From<io::Error>,
+    F: Fn(&S::SinkItem) -> usize
+{
+    type SinkItem = S::SinkItem;
+    type SinkError = S::SinkError;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let cost = (self.cost)(&item);
+        while self.credit < cost {
+            match self.lim.acquire(self.id, cost - self.credit) {
+                Ok(t) => self.credit += t.consume(),
+                Err(Error::NoCapacity) => {
+                    self.lim.enqueue(self.id).map_err(io::Error::from)?;
+                    return Ok(AsyncSink::NotReady(item))
+                }
+                Err(e) => return Err(io::Error::from(e).into())
+            }
+        }
+        let result = self.sink.start_send(item)?;
+        if result.is_ready() {
+            self.credit -= cost
+        }
+        Ok(result)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.sink.poll_complete()
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        self.sink.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use super::*;
+
+    #[test]
+    fn items_are_paced() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(10);
+            let sink = Vec::<Vec<u8>>::new().sink_map_err(|()| io::Error::other("vec"));
+            let mut s = LimitedSink::new(sink, lim, |item: &Vec<u8>| item.len()).unwrap();
+            let mut sent = Vec::new();
+            for _ in 0 .. 4 {
+                let mut n = 0;
+                while s.start_send(vec![0; 4]).unwrap().is_ready() {
+                    n += 1
+                }
+                sent.push(n);
+                clock.tick();
+            }
+            assert_eq!(vec![2, 3, 2, 3], sent);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+}
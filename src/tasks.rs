@@ -0,0 +1,414 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::algorithms::Id;
+#[cfg(feature = "lock-free")]
+use crossbeam_queue::SegQueue;
+#[cfg(feature = "wait-stats")]
+use crate::limiter::WaitStats;
+use futures::task::Task;
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::HashMap;
+#[cfg(feature = "compat")]
+use std::task::Waker;
+#[cfg(feature = "wait-stats")]
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+/// The time at which a task has been enqueued, if wait statistics are enabled.
+#[cfg(feature = "wait-stats")]
+pub(crate) type Stamp = Instant;
+
+/// The time at which a task has been enqueued, if wait statistics are enabled.
+#[cfg(not(feature = "wait-stats"))]
+pub(crate) type Stamp = ();
+
+/// Get the current `Stamp`.
+pub(crate) fn stamp() -> Stamp {
+    #[cfg(feature = "wait-stats")]
+    return Instant::now();
+}
+
+/// The number of tasks which may wait on behalf of the same part at once,
+/// one per slot, e.g. for the two directions of a duplex resource whose
+/// reads and writes are polled by different tasks.
+pub(crate) const SLOTS: usize = 2;
+
+/// A task waiting for capacity, either a futures 0.1 `Task` or, with the
+/// `compat` feature, the `Waker` of a `std::future`.
+#[derive(Debug)]
+pub(crate) enum Waiter {
+    Task(Task),
+    #[cfg(feature = "compat")]
+    Waker(Waker),
+}
+
+impl Waiter {
+    pub(crate) fn notify(self) {
+        match self {
+            Waiter::Task(t) => t.notify(),
+            #[cfg(feature = "compat")]
+            Waiter::Waker(w) => w.wake()
+        }
+    }
+}
+
+/// The set of waiting tasks, shared between parts and the timer.
+///
+/// By default, every update locks the set. With the `lock-free` feature,
+/// updates are pushed onto a lock-free queue instead and applied in order
+/// when the timer drains the set, so enqueueing parts do not contend with
+/// each other or with the timer.
+#[derive(Debug)]
+pub(crate) struct SharedTasks {
+    tasks: Mutex<Tasks>,
+    #[cfg(feature = "lock-free")]
+    updates: SegQueue<Update>,
+}
+
+/// A deferred update of the set of waiting tasks.
+#[cfg(feature = "lock-free")]
+#[derive(Debug)]
+enum Update {
+    Insert(Id, usize, Waiter, Stamp),
+    Served(Id),
+    Remove(Id),
+}
+
+impl SharedTasks {
+    pub(crate) fn new(tasks: Tasks) -> SharedTasks {
+        SharedTasks {
+            tasks: Mutex::new(tasks),
+            #[cfg(feature = "lock-free")]
+            updates: SegQueue::new(),
+        }
+    }
+
+    /// The maximum number of notifications to skip.
+    pub(crate) fn max_skips(&self) -> u32 {
+        self.tasks.lock().max_skips()
+    }
+
+    /// Enqueue the task of the given part in the given slot.
+    #[cfg(not(feature = "lock-free"))]
+    #[allow(clippy::unit_arg)] // `Stamp` is `()` without the `wait-stats` feature
+    pub(crate) fn insert(&self, id: Id, slot: usize, task: Waiter) {
+        self.tasks.lock().insert(id, slot, task, stamp())
+    }
+
+    /// Enqueue the task of the given part in the given slot.
+    #[cfg(feature = "lock-free")]
+    #[allow(clippy::unit_arg)] // `Stamp` is `()` without the `wait-stats` feature
+    pub(crate) fn insert(&self, id: Id, slot: usize, task: Waiter) {
+        self.updates.push(Update::Insert(id, slot, task, stamp()))
+    }
+
+    /// Record that the given part has been served.
+    #[cfg(not(feature = "lock-free"))]
+    pub(crate) fn served(&self, id: Id) {
+        self.tasks.lock().served(id)
+    }
+
+    /// Record that the given part has been served.
+    #[cfg(feature = "lock-free")]
+    pub(crate) fn served(&self, id: Id) {
+        self.updates.push(Update::Served(id))
+    }
+
+    /// Forget everything about the given part.
+    #[cfg(not(feature = "lock-free"))]
+    pub(crate) fn remove(&self, id: Id) {
+        self.tasks.lock().remove(id)
+    }
+
+    /// Forget everything about the given part.
+    #[cfg(feature = "lock-free")]
+    pub(crate) fn remove(&self, id: Id) {
+        self.updates.push(Update::Remove(id))
+    }
+
+    /// Remove the task of the given part waiting in the given slot, if any,
+    /// e.g. to notify it right away.
+    pub(crate) fn take(&self, id: Id, slot: usize) -> Option<Waiter> {
+        self.lock().take(id, slot)
+    }
+
+    /// Remove all tasks which are due to be notified (or all tasks if
+    /// `all` is set), in order of arrival, together with the number of
+    /// tasks which were waiting.
+    pub(crate) fn drain(&self, all: bool) -> (Vec<(Id, Waiter)>, usize) {
+        let mut tasks = self.lock();
+        let waiting = tasks.len();
+        (tasks.drain(all), waiting)
+    }
+
+    /// The parts whose tasks are waiting, in order of arrival.
+    pub(crate) fn waiting(&self) -> Vec<Id> {
+        self.lock().waiting()
+    }
+
+    /// Lock the set of tasks, with all deferred updates applied.
+    fn lock(&self) -> MutexGuard<'_, Tasks> {
+        #[cfg_attr(not(feature = "lock-free"), allow(unused_mut))]
+        let mut tasks = self.tasks.lock();
+        #[cfg(feature = "lock-free")]
+        while let Ok(update) = self.updates.pop() {
+            match update {
+                Update::Insert(id, slot, task, stamp) => tasks.insert(id, slot, task, stamp),
+                Update::Served(id) => tasks.served(id),
+                Update::Remove(id) => tasks.remove(id),
+            }
+        }
+        tasks
+    }
+
+    /// Summarise the recorded wait durations.
+    #[cfg(feature = "wait-stats")]
+    pub(crate) fn wait_stats(&self) -> WaitStats {
+        self.tasks.lock().waits.stats()
+    }
+
+    /// Forget the recorded wait durations.
+    #[cfg(feature = "wait-stats")]
+    pub(crate) fn reset_wait_stats(&self) {
+        self.tasks.lock().waits = Waits::default()
+    }
+}
+
+/// The set of tasks waiting for capacity.
+///
+/// Tasks are notified in order of arrival. A part which has been notified
+/// but did not get any capacity keeps its original arrival number when it
+/// enqueues again, so the parts served most recently go to the back of the
+/// queue and nobody is consistently served last.
+///
+/// With backoff enabled, a part which keeps enqueueing again after being
+/// notified without being served skips an exponentially growing number of
+/// notifications, up to the configured maximum.
+#[derive(Debug, Default)]
+pub(crate) struct Tasks {
+    seqno: u64, // next arrival number
+    waiting: HashMap<Id, (u64, Slots)>, // enqueued tasks and their arrival number
+    arrival: HashMap<Id, u64>, // arrival numbers of parts not served since
+    max_skips: u32, // upper bound of notifications to skip
+    failures: HashMap<Id, Failures>, // parts notified but not served since
+    #[cfg(feature = "wait-stats")]
+    since: HashMap<Id, Instant>, // time at which waiting parts have been enqueued
+    #[cfg(feature = "wait-stats")]
+    waits: Waits, // durations from enqueueing to notification
+    #[cfg(feature = "tracing")]
+    spans: HashMap<Id, tracing::Span>, // spans from enqueueing to notification
+}
+
+/// The tasks waiting on behalf of a part, by slot.
+type Slots = [Option<Waiter>; SLOTS];
+
+/// The most recent wait durations.
+#[cfg(feature = "wait-stats")]
+#[derive(Debug, Default)]
+struct Waits {
+    count: u64, // total number of waits
+    max: Duration, // longest wait
+    recent: VecDeque<Duration>, // the last `Waits::SAMPLES` waits
+}
+
+#[cfg(feature = "wait-stats")]
+impl Waits {
+    const SAMPLES: usize = 1024;
+
+    fn record(&mut self, d: Duration) {
+        if self.recent.len() == Waits::SAMPLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(d);
+        self.count += 1;
+        self.max = std::cmp::max(self.max, d)
+    }
+
+    /// Summarise the waits. Percentiles are based on the most recent waits.
+    fn stats(&self) -> WaitStats {
+        let mut recent = self.recent.iter().cloned().collect::<Vec<_>>();
+        recent.sort();
+        let percentile = |p: usize| {
+            recent.get(recent.len() * p / 100).cloned().unwrap_or_default()
+        };
+        WaitStats { count: self.count, p50: percentile(50), p99: percentile(99), max: self.max }
+    }
+}
+
+/// Consecutive failures of a part to get served after being notified.
+#[derive(Debug, Default)]
+struct Failures {
+    count: u32, // number of consecutive failures
+    skip: u32, // notifications still to skip
+}
+
+impl Tasks {
+    /// Create an empty set of tasks which skips at most `max_skips`
+    /// notifications of parts which repeatedly fail to get served.
+    pub(crate) fn with_backoff(max_skips: u32) -> Tasks {
+        Tasks { max_skips, .. Tasks::default() }
+    }
+
+    /// Preallocate space for the given number of parts.
+    pub(crate) fn with_expected_parts(mut self, n: usize) -> Tasks {
+        self.waiting.reserve(n);
+        self.arrival.reserve(n);
+        self.failures.reserve(n);
+        #[cfg(feature = "wait-stats")]
+        self.since.reserve(n);
+        #[cfg(feature = "tracing")]
+        self.spans.reserve(n);
+        self
+    }
+
+    /// The maximum number of notifications to skip.
+    pub(crate) fn max_skips(&self) -> u32 {
+        self.max_skips
+    }
+
+    /// The number of waiting tasks.
+    pub(crate) fn len(&self) -> usize {
+        self.waiting.values().map(|(_, slots)| slots.iter().flatten().count()).sum()
+    }
+
+    /// The parts whose tasks are waiting, in order of arrival.
+    pub(crate) fn waiting(&self) -> Vec<Id> {
+        let mut ids = self.waiting.iter().map(|(id, (n, _))| (*n, *id)).collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Enqueue the task of the given part in the given slot, replacing the
+    /// task which waited in that slot before, if any.
+    #[cfg_attr(not(feature = "wait-stats"), allow(unused_variables))]
+    pub(crate) fn insert(&mut self, id: Id, slot: usize, task: Waiter, stamp: Stamp) {
+        let seqno = &mut self.seqno;
+        let n = *self.arrival.entry(id).or_insert_with(|| {
+            *seqno += 1;
+            *seqno
+        });
+        if self.max_skips > 0 {
+            if let Some(f) = self.failures.get_mut(&id) {
+                f.count = f.count.saturating_add(1);
+                f.skip = 2u32.saturating_pow(f.count).saturating_sub(1).min(self.max_skips)
+            }
+        }
+        #[cfg(feature = "wait-stats")]
+        self.since.entry(id).or_insert(stamp);
+        #[cfg(feature = "tracing")]
+        self.spans.entry(id).or_insert_with(|| {
+            let span = tracing::trace_span!("wait", part = %id);
+            span.in_scope(|| tracing::trace!("waiting for capacity"));
+            span
+        });
+        self.waiting.entry(id).or_insert((n, [None, None])).1[slot] = Some(task)
+    }
+
+    /// Remove the task of the given part waiting in the given slot, if any.
+    /// The part stops waiting once it has no task left.
+    pub(crate) fn take(&mut self, id: Id, slot: usize) -> Option<Waiter> {
+        let (_, slots) = self.waiting.get_mut(&id)?;
+        let task = slots[slot].take();
+        if slots.iter().all(Option::is_none) {
+            self.waiting.remove(&id);
+            self.notified(id)
+        }
+        task
+    }
+
+    /// Record that the given part has been served, i.e. the next time it
+    /// enqueues, it goes to the back of the queue.
+    pub(crate) fn served(&mut self, id: Id) {
+        self.arrival.remove(&id);
+        self.failures.remove(&id);
+    }
+
+    /// Forget everything about the given part.
+    pub(crate) fn remove(&mut self, id: Id) {
+        self.waiting.remove(&id);
+        self.arrival.remove(&id);
+        self.failures.remove(&id);
+        #[cfg(feature = "wait-stats")]
+        self.since.remove(&id);
+        #[cfg(feature = "tracing")]
+        self.spans.remove(&id);
+    }
+
+    /// Remove all waiting tasks which are due to be notified, in order of
+    /// arrival. Unless `all` is set, tasks which back off stay and are due
+    /// on a later call.
+    pub(crate) fn drain(&mut self, all: bool) -> Vec<(Id, Waiter)> {
+        let mut due = Vec::with_capacity(self.waiting.len());
+        // drain in place to keep the allocated space
+        let waiting = self.waiting.drain().collect::<Vec<_>>();
+        for (id, entry) in waiting {
+            match self.failures.get_mut(&id) {
+                Some(f) if f.skip > 0 && !all => {
+                    f.skip -= 1;
+                    self.waiting.insert(id, entry);
+                }
+                Some(_) => due.push((id, entry)),
+                None => {
+                    if self.max_skips > 0 {
+                        self.failures.insert(id, Failures::default());
+                    }
+                    due.push((id, entry))
+                }
+            }
+        }
+        for (id, _) in &due {
+            self.notified(*id)
+        }
+        due.sort_by_key(|(_, (n, _))| *n);
+        due.into_iter()
+            .flat_map(|(id, (_, slots))| IntoIterator::into_iter(slots).flatten().map(move |t| (id, t)))
+            .collect()
+    }
+
+    /// Record that the given part is no longer waiting, as its tasks have
+    /// been notified.
+    #[cfg_attr(not(any(feature = "wait-stats", feature = "tracing")), allow(unused_variables))]
+    fn notified(&mut self, id: Id) {
+        #[cfg(feature = "wait-stats")]
+        if let Some(t) = self.since.remove(&id) {
+            self.waits.record(t.elapsed())
+        }
+        // the span of a part's wait ends once it is notified
+        #[cfg(feature = "tracing")]
+        if let Some(span) = self.spans.remove(&id) {
+            span.in_scope(|| tracing::debug!("notified"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{future::{self, Future}, task};
+    use super::*;
+
+    #[test]
+    #[allow(clippy::unit_arg)] // `Stamp` is `()` without the `wait-stats` feature
+    fn expected_parts_are_preallocated() {
+        let mut tasks = Tasks::with_backoff(3).with_expected_parts(64);
+        let allocated = tasks.waiting.capacity();
+        assert!(allocated >= 64);
+        future::lazy(|| {
+            let bucket = crate::algorithms::bucket::Bucket::new(100);
+            for _ in 0 .. 64 {
+                tasks.insert(bucket.add_part().unwrap(), 0, Waiter::Task(task::current()), stamp())
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(64, tasks.drain(false).len());
+        assert_eq!(allocated, tasks.waiting.capacity())
+    }
+}
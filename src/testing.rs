@@ -0,0 +1,31 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+//! Helpers shared by the tests of several modules.
+
+use futures::executor::Notify;
+use parking_lot::Mutex;
+
+/// Records the IDs of notified tasks in order.
+#[derive(Default)]
+pub(crate) struct Wakeups(pub(crate) Mutex<Vec<usize>>);
+
+impl Wakeups {
+    /// The number of notifications so far.
+    pub(crate) fn count(&self) -> usize {
+        self.0.lock().len()
+    }
+}
+
+impl Notify for Wakeups {
+    fn notify(&self, id: usize) {
+        self.0.lock().push(id)
+    }
+}
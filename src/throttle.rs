@@ -0,0 +1,123 @@
+// Copyright 2018 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0 or MIT license, at your option.
+//
+// A copy of the Apache License, Version 2.0 is included in the software as
+// LICENSE-APACHE and a copy of the MIT license is included in the software
+// as LICENSE-MIT. You may also obtain a copy of the Apache License, Version 2.0
+// at https://www.apache.org/licenses/LICENSE-2.0 and a copy of the MIT license
+// at https://opensource.org/licenses/MIT.
+
+use crate::{algorithms::{Id, Token}, error::{Error, Result}, limiter::Limiter};
+use futures::prelude::*;
+use std::io;
+
+/// A `Future` which is only run once its cost has been covered by capacity
+/// of a `Limiter`, see `Limiter::throttle`.
+///
+/// Capacity is accumulated across time windows, so futures which cost more
+/// than a single window's share still make progress. If the future is
+/// dropped before it runs, the capacity acquired so far is given back.
+#[derive(Debug)]
+pub struct Throttled<F> {
+    id: Id,
+    future: F,
+    lim: Limiter,
+    cost: usize,
+    acquired: Vec<Token>, // capacity acquired before the future runs
+    running: bool,
+}
+
+impl<F: Future> Throttled<F> {
+    pub(crate) fn new(future: F, lim: Limiter, cost: usize) -> Result<Throttled<F>> {
+        let id = lim.register()?;
+        Ok(Throttled { id, future, lim, cost, acquired: Vec::new(), running: false })
+    }
+}
+
+impl<F> Drop for Throttled<F> {
+    fn drop(&mut self) {
+        for t in self.acquired.drain(..) {
+            self.lim.release(t)
+        }
+        self.lim.deregister(self.id)
+    }
+}
+
+impl<F> Future for Throttled<F>
+where
+    F: Future,
+    F::Error: From<io::Error>
+{
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !self.running {
+            let mut credit = self.acquired.iter().map(Token::get).sum::<usize>();
+            while credit < self.cost {
+                match self.lim.acquire(self.id, self.cost - credit) {
+                    Ok(t) => {
+                        credit += t.get();
+                        self.acquired.push(t)
+                    }
+                    Err(Error::NoCapacity) => {
+                        self.lim.enqueue(self.id).map_err(io::Error::from)?;
+                        return Ok(Async::NotReady)
+                    }
+                    Err(e) => return Err(io::Error::from(e).into())
+                }
+            }
+            // the capacity is spent
+            for t in self.acquired.drain(..) {
+                t.consume();
+            }
+            self.running = true
+        }
+        self.future.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use super::*;
+
+    #[test]
+    fn futures_are_paced() {
+        future::lazy(|| {
+            let (lim, mut clock) = Limiter::with_manual_clock(10);
+            let mut futures = (0 .. 10)
+                .map(|k| lim.throttle(future::ok::<_, io::Error>(k), 4).unwrap())
+                .collect::<Vec<_>>();
+            let mut done = 0;
+            for i in 1 ..= 4 {
+                futures.retain_mut(|f| f.poll().unwrap().is_not_ready());
+                done = 10 - futures.len();
+                // at most the capacity of the time windows so far has been spent
+                assert!(4 * done <= 10 * i, "{} futures done after {} windows", done, i);
+                clock.tick();
+            }
+            assert_eq!(10, done);
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    fn dropping_releases_acquired_capacity() {
+        future::lazy(|| {
+            let (lim, _clock) = Limiter::with_manual_clock(10);
+            let mut f = lim.throttle(future::ok::<(), io::Error>(()), 50).unwrap();
+            assert!(f.poll().unwrap().is_not_ready());
+            assert_eq!(0, lim.available());
+            drop(f);
+            assert_eq!(10, lim.available());
+            assert_eq!(0, lim.parts());
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap()
+    }
+}